@@ -0,0 +1,210 @@
+use kernel::prelude::*;
+use kernel::file_operations::{self, FileOpenFlag, Kiocb, PollFlags, PollTable, SeekFrom};
+use kernel::cdev::CDev;
+use kernel::iov_iter::IovIter;
+use kernel::pipe::PipeInodeInfo;
+use kernel::mm::VmArea;
+use kernel::cred::{self, Uid};
+use kernel::security::{capable, CAP_DAC_OVERRIDE};
+use kernel::sync::SpinLock;
+use kernel::waitqueue::WaitQueue;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{ScullDev, ScullModule};
+
+pub(crate) const SCULL_A_NR_DEVS: usize = 3; // scullsingle, sculluid, scullwuid
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AccessPolicy {
+    Single,  // Only one opener at a time
+    Uid,     // Only one user at a time, EBUSY for the others
+    WaitUid, // Only one user at a time, the others sleep until it is released
+}
+
+#[derive(Debug)]
+struct Owner {
+    count: usize,
+    uid: Uid,
+}
+
+// `dev` must stay the first field: the read/write paths shared with plain
+// scull look the private data up as a `ScullDev`.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct ScullAccess {
+    dev: ScullDev,
+    policy: AccessPolicy,
+    available: AtomicBool, // Used by the single-open policy
+    owner: SpinLock<Owner>, // Used by the uid policies
+    waitq: WaitQueue, // Scullwuid openers wait here for the owner to go away
+    cdev: CDev,
+}
+
+impl ScullAccess {
+    pub(crate) fn new(policy: AccessPolicy) -> Result<Self> {
+        Ok(ScullAccess {
+            dev: ScullDev::new()?,
+            policy,
+            available: AtomicBool::new(true),
+            owner: SpinLock::new(Owner { count: 0, uid: Uid::default() }),
+            waitq: WaitQueue::new(),
+            cdev: CDev::with_ops::<ScullAccessOps>(),
+        })
+    }
+
+    pub(crate) fn cdev(&mut self) -> &mut CDev {
+        &mut self.cdev
+    }
+
+    pub(crate) fn trim(&mut self) -> Result {
+        self.dev.trim()
+    }
+
+    // The device is free if nobody has it open, or the opener is the owner
+    // (or root, who can always get in)
+    fn uid_available(owner: &Owner) -> bool {
+        owner.count == 0
+            || owner.uid == cred::current_uid()
+            || owner.uid == cred::current_euid()
+            || capable(CAP_DAC_OVERRIDE)
+    }
+
+    fn acquire(&self, ctx: &kernel::file_operations::FileContext) -> Result {
+        match self.policy {
+            AccessPolicy::Single => {
+                if self.available.compare_exchange(true, false, Ordering::AcqRel, Ordering::Acquire).is_err() {
+                    return Err(Error::EBUSY); // Already open
+                }
+            }
+            AccessPolicy::Uid => {
+                let mut owner = self.owner.lock();
+                if !Self::uid_available(&owner) {
+                    return Err(Error::EBUSY);
+                }
+                if owner.count == 0 {
+                    owner.uid = cred::current_uid(); // Grab it
+                }
+                owner.count += 1;
+            }
+            AccessPolicy::WaitUid => {
+                let mut owner = self.owner.lock();
+                while !Self::uid_available(&owner) {
+                    drop(owner);
+                    if ctx.flags().contains(FileOpenFlag::NONBLOCK) {
+                        return Err(Error::EAGAIN);
+                    }
+                    if self.waitq.wait_event_interruptible(|| Self::uid_available(&self.owner.lock())).is_err() {
+                        return Err(Error::ERESTARTSYS);
+                    }
+                    owner = self.owner.lock();
+                }
+                if owner.count == 0 {
+                    owner.uid = cred::current_uid();
+                }
+                owner.count += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn release(&self) {
+        match self.policy {
+            AccessPolicy::Single => self.available.store(true, Ordering::Release),
+            AccessPolicy::Uid => self.owner.lock().count -= 1,
+            AccessPolicy::WaitUid => {
+                let count = {
+                    let mut owner = self.owner.lock();
+                    owner.count -= 1;
+                    owner.count
+                };
+                if count == 0 {
+                    self.waitq.wake_up_interruptible_sync(); // Awake other uid's
+                }
+            }
+        }
+    }
+}
+
+pub(crate) struct ScullAccessOps;
+
+impl file_operations::FileOpener for ScullAccessOps {
+    fn open(ctx: &kernel::file_operations::FileContext) -> Result {
+        pr_debug!("scull access open() is invoked\n");
+
+        let acc = &mut ctx.private_data().as_mut::<ScullAccess>();
+        acc.acquire(ctx)?;
+
+        // Policy is satisfied, the rest is plain scull. The device lives as long
+        // as the module, so the file takes no reference.
+        if let Err(e) = acc.dev.open(ctx) {
+            acc.release();
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+impl file_operations::FileCloser for ScullAccessOps {
+    fn release(ctx: &kernel::file_operations::FileContext) -> Result {
+        pr_debug!("scull access release() is invoked\n");
+
+        let acc = &mut ctx.private_data().as_mut::<ScullAccess>();
+        let ret = acc.dev.release(ctx);
+        acc.release();
+        ret
+    }
+}
+
+impl file_operations::FileReadIter for ScullAccessOps {
+    fn read_iter(kiocb: &mut Kiocb, iter: &mut IovIter) -> Result<usize> {
+        <ScullModule as file_operations::FileReadIter>::read_iter(kiocb, iter)
+    }
+}
+
+impl file_operations::FileWriteIter for ScullAccessOps {
+    fn write_iter(kiocb: &mut Kiocb, iter: &mut IovIter) -> Result<usize> {
+        <ScullModule as file_operations::FileWriteIter>::write_iter(kiocb, iter)
+    }
+}
+
+impl file_operations::FileSpliceRead for ScullAccessOps {
+    fn splice_read(ctx: &kernel::file_operations::FileContext, pos: &mut u64, pipe: &mut PipeInodeInfo, len: usize, flags: u32) -> Result<usize> {
+        <ScullModule as file_operations::FileSpliceRead>::splice_read(ctx, pos, pipe, len, flags)
+    }
+}
+
+impl file_operations::FileSpliceWrite for ScullAccessOps {
+    fn splice_write(pipe: &mut PipeInodeInfo, ctx: &kernel::file_operations::FileContext, pos: &mut u64, len: usize, flags: u32) -> Result<usize> {
+        <ScullModule as file_operations::FileSpliceWrite>::splice_write(pipe, ctx, pos, len, flags)
+    }
+}
+
+impl file_operations::FileSeeker for ScullAccessOps {
+    fn seek(ctx: &kernel::file_operations::FileContext, pos: SeekFrom) -> Result<u64> {
+        <ScullModule as file_operations::FileSeeker>::seek(ctx, pos)
+    }
+}
+
+impl file_operations::FilePoller for ScullAccessOps {
+    fn poll(ctx: &kernel::file_operations::FileContext, table: &PollTable) -> Result<PollFlags> {
+        <ScullModule as file_operations::FilePoller>::poll(ctx, table)
+    }
+}
+
+impl file_operations::FileFasync for ScullAccessOps {
+    fn fasync(ctx: &kernel::file_operations::FileContext, fd: i32, on: bool) -> Result {
+        <ScullModule as file_operations::FileFasync>::fasync(ctx, fd, on)
+    }
+}
+
+impl file_operations::FileMmap for ScullAccessOps {
+    fn mmap(ctx: &kernel::file_operations::FileContext, vma: &mut VmArea) -> Result {
+        <ScullModule as file_operations::FileMmap>::mmap(ctx, vma)
+    }
+}
+
+impl file_operations::FileIoctl for ScullAccessOps {
+    fn ioctl(ctx: &kernel::file_operations::FileContext, cmd: u32, arg: usize) -> Result<i32> {
+        <ScullModule as file_operations::FileIoctl>::ioctl(ctx, cmd, arg)
+    }
+}
@@ -0,0 +1,259 @@
+use kernel::prelude::*;
+use kernel::crc32::crc32_le;
+use kernel::debugfs::{self, DebugfsBuf, Dir};
+use kernel::iov_iter::IovIter;
+use kernel::pages::{Page, PAGE_SIZE};
+use kernel::slab::{CacheObject, KmemCache};
+use kernel::sync::Arc;
+use kernel::time::ktime_get_ns;
+use kernel::vmalloc::VmallocBuf;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// scullc devices take the minors after scull-control, and their blocks come
+// from a cache of their own so that they show up in /proc/slabinfo
+pub(crate) const SCULLC_NR_DEVS: usize = 4;
+pub(crate) const SCULLC_CACHE_NAME: &str = "scullc";
+
+// Where a device gets the memory of its blocks from
+#[derive(Debug, Clone)]
+pub(crate) enum ScullBackend {
+    Page,                  // scull: one page at a time, blocks can be mmapped
+    Pages(u32),            // scullp: one allocation of 2^order pages per block
+    Vmalloc,               // scullv: one virtually contiguous area per block
+    Cache(Arc<KmemCache>), // scullc: one object per block, the cache goes with the last device
+}
+
+// Names taken by the scull_backends parameter, in `kind` order
+pub(crate) const SCULL_BACKEND_NAMES: [&str; 4] = ["scull", "scullp", "scullv", "scullc"];
+
+impl ScullBackend {
+    fn kind(&self) -> usize {
+        match self {
+            ScullBackend::Page => 0,
+            ScullBackend::Pages(_) => 1,
+            ScullBackend::Vmalloc => 2,
+            ScullBackend::Cache(_) => 3,
+        }
+    }
+
+    // Backend for a name of the scull_backends parameter. scullc devices have
+    // nodes of their own, so the cache can't be picked here.
+    pub(crate) fn from_name(name: &str, order: u32) -> Result<Self> {
+        match name {
+            "scull" => Ok(ScullBackend::Page),
+            "scullp" => Ok(ScullBackend::Pages(order)),
+            "scullv" => Ok(ScullBackend::Vmalloc),
+            _ => Err(Error::EINVAL),
+        }
+    }
+
+    // Block size the backend is stuck with, if any
+    pub(crate) fn fixed_quantum(&self) -> Option<usize> {
+        match self {
+            ScullBackend::Page | ScullBackend::Vmalloc => None,
+            ScullBackend::Pages(order) => Some(PAGE_SIZE << order),
+            ScullBackend::Cache(cache) => Some(cache.object_size()),
+        }
+    }
+
+    // Slab objects aren't page aligned, and high-order allocations aren't split
+    // into pages that could be handed out one by one
+    pub(crate) fn mappable(&self) -> bool {
+        matches!(self, ScullBackend::Page | ScullBackend::Pages(0) | ScullBackend::Vmalloc)
+    }
+
+    pub(crate) fn alloc(&self, quantum: usize) -> Result<ScullMem> {
+        let start = ktime_get_ns();
+        let mem = match self {
+            ScullBackend::Page => {
+                let mut pages = Vec::try_with_capacity(quantum / PAGE_SIZE)?;
+                for _ in 0..quantum / PAGE_SIZE {
+                    pages.try_push(Page::alloc_zeroed()?)?;
+                }
+                ScullMem::Pages { pages, order: 0 }
+            }
+            ScullBackend::Pages(order) => {
+                let mut pages = Vec::try_with_capacity(1)?;
+                pages.try_push(Page::alloc_order_zeroed(*order)?)?;
+                ScullMem::Pages { pages, order: *order }
+            }
+            ScullBackend::Vmalloc => ScullMem::Vmalloc(VmallocBuf::alloc_zeroed(quantum)?),
+            ScullBackend::Cache(cache) => ScullMem::Cache(cache.alloc_zeroed()?),
+        };
+        SCULL_ALLOC_STATS[self.kind()].record(ktime_get_ns() - start);
+        Ok(mem)
+    }
+}
+
+// Latency of the successful block allocations of one backend
+#[derive(Debug)]
+struct AllocStats {
+    count: AtomicU64,
+    total_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl AllocStats {
+    const fn new() -> Self {
+        AllocStats {
+            count: AtomicU64::new(0),
+            total_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, ns: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.total_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    // (allocations, total ns, max ns)
+    fn read(&self) -> (u64, u64, u64) {
+        (
+            self.count.load(Ordering::Relaxed),
+            self.total_ns.load(Ordering::Relaxed),
+            self.max_ns.load(Ordering::Relaxed),
+        )
+    }
+
+    fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+        self.total_ns.store(0, Ordering::Relaxed);
+        self.max_ns.store(0, Ordering::Relaxed);
+    }
+}
+
+// Indexed like SCULL_BACKEND_NAMES
+static SCULL_ALLOC_STATS: [AllocStats; 4] =
+    [AllocStats::new(), AllocStats::new(), AllocStats::new(), AllocStats::new()];
+
+// A table doesn't belong in sysfs, so the stats go to debugfs, next to the
+// fault injection knobs
+pub(crate) fn register_alloc_stats(root: &Dir) -> Result {
+    root.create_file("alloc_latency", &SCULL_ALLOC_STATS, alloc_latency_show, alloc_latency_store)?;
+    Ok(())
+}
+
+// One line per backend: name, allocations, average and worst latency in ns
+fn alloc_latency_show(all: &[AllocStats; 4], buf: &mut DebugfsBuf) -> Result<usize> {
+    let mut len = 0;
+    for (name, stats) in SCULL_BACKEND_NAMES.iter().zip(all.iter()) {
+        let (count, total_ns, max_ns) = stats.read();
+        let avg_ns = if count == 0 { 0 } else { total_ns / count };
+        len += debugfs::emit!(buf, "{} {} {} {}\n", name, count, avg_ns, max_ns);
+    }
+    Ok(len)
+}
+
+// Writing 0 starts a new measurement
+fn alloc_latency_store(all: &[AllocStats; 4], buf: &[u8]) -> Result<usize> {
+    if core::str::from_utf8(buf).map_err(|_| Error::EINVAL)?.trim() != "0" {
+        return Err(Error::EINVAL);
+    }
+    for stats in all.iter() {
+        stats.reset();
+    }
+    Ok(buf.len())
+}
+
+// Memory of one block. Accessors work within one contiguous chunk, callers
+// split their ranges with `chunk`.
+#[derive(Debug)]
+pub(crate) enum ScullMem {
+    Pages { pages: Vec<Page>, order: u32 }, // Each entry is 2^order pages
+    Vmalloc(VmallocBuf),
+    Cache(CacheObject), // Goes back to its cache on drop
+}
+
+impl ScullMem {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            ScullMem::Pages { pages, order } => pages.len() * (PAGE_SIZE << order),
+            ScullMem::Vmalloc(buf) => buf.len(),
+            ScullMem::Cache(obj) => obj.len(),
+        }
+    }
+
+    // Bytes from `pos` to the end of its chunk
+    pub(crate) fn chunk(&self, pos: usize) -> usize {
+        match self {
+            ScullMem::Pages { order, .. } => (PAGE_SIZE << order) - pos % (PAGE_SIZE << order),
+            ScullMem::Vmalloc(buf) => buf.len() - pos,
+            ScullMem::Cache(obj) => obj.len() - pos,
+        }
+    }
+
+    pub(crate) fn read_slice(&self, pos: usize, buf: &mut [u8]) {
+        match self {
+            ScullMem::Pages { pages, order } => {
+                let size = PAGE_SIZE << order;
+                pages[pos / size].read_slice(pos % size, buf)
+            }
+            ScullMem::Vmalloc(vbuf) => buf.copy_from_slice(&vbuf.as_slice()[pos..pos + buf.len()]),
+            ScullMem::Cache(obj) => buf.copy_from_slice(&obj.as_slice()[pos..pos + buf.len()]),
+        }
+    }
+
+    pub(crate) fn write_slice(&mut self, pos: usize, buf: &[u8]) {
+        match self {
+            ScullMem::Pages { pages, order } => {
+                let size = PAGE_SIZE << *order;
+                pages[pos / size].write_slice(pos % size, buf)
+            }
+            ScullMem::Vmalloc(vbuf) => vbuf.as_mut_slice()[pos..pos + buf.len()].copy_from_slice(buf),
+            ScullMem::Cache(obj) => obj.as_mut_slice()[pos..pos + buf.len()].copy_from_slice(buf),
+        }
+    }
+
+    pub(crate) fn zero_range(&mut self, pos: usize, len: usize) {
+        match self {
+            ScullMem::Pages { pages, order } => {
+                let size = PAGE_SIZE << *order;
+                pages[pos / size].zero_range(pos % size, len)
+            }
+            ScullMem::Vmalloc(vbuf) => vbuf.as_mut_slice()[pos..pos + len].fill(0),
+            ScullMem::Cache(obj) => obj.as_mut_slice()[pos..pos + len].fill(0),
+        }
+    }
+
+    pub(crate) fn copy_to_iter(&self, pos: usize, len: usize, iter: &mut IovIter) -> usize {
+        match self {
+            ScullMem::Pages { pages, order } => {
+                let size = PAGE_SIZE << order;
+                pages[pos / size].copy_to_iter(pos % size, len, iter)
+            }
+            ScullMem::Vmalloc(vbuf) => iter.copy_to(&vbuf.as_slice()[pos..pos + len]),
+            ScullMem::Cache(obj) => iter.copy_to(&obj.as_slice()[pos..pos + len]),
+        }
+    }
+
+    pub(crate) fn copy_from_iter(&mut self, pos: usize, len: usize, iter: &mut IovIter) -> usize {
+        match self {
+            ScullMem::Pages { pages, order } => {
+                let size = PAGE_SIZE << *order;
+                pages[pos / size].copy_from_iter(pos % size, len, iter)
+            }
+            ScullMem::Vmalloc(vbuf) => iter.copy_from(&mut vbuf.as_mut_slice()[pos..pos + len]),
+            ScullMem::Cache(obj) => iter.copy_from(&mut obj.as_mut_slice()[pos..pos + len]),
+        }
+    }
+
+    pub(crate) fn checksum(&self) -> u32 {
+        match self {
+            ScullMem::Pages { pages, .. } => pages.iter().fold(!0, |crc, page| page.with_data(|data| crc32_le(crc, data))),
+            ScullMem::Vmalloc(vbuf) => crc32_le(!0, vbuf.as_slice()),
+            ScullMem::Cache(obj) => crc32_le(!0, obj.as_slice()),
+        }
+    }
+
+    // The page holding byte `pos`, for mmap
+    pub(crate) fn page(&self, pos: usize) -> Option<&Page> {
+        match self {
+            ScullMem::Pages { pages, order: 0 } => Some(&pages[pos / PAGE_SIZE]),
+            ScullMem::Pages { .. } => None,
+            ScullMem::Vmalloc(vbuf) => Some(vbuf.page(pos / PAGE_SIZE)),
+            ScullMem::Cache(_) => None,
+        }
+    }
+}
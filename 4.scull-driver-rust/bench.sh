@@ -0,0 +1,33 @@
+#! /bin/sh
+# Time a 100 MB sequential write and read-back on a scull device.
+#
+# The run is repeated with qset=1, where every qset holds a single block and
+# locating an offset walks one node per block like the old linked list did,
+# and with the default qset of 1000.
+device=${1:-/dev/scull0}
+size_mb=100
+bs=4096
+count=$((size_mb * 1024 * 1024 / bs))
+
+SCULL_IOCTQSET=0x6b04   # _IO('k', 4)
+SCULL_IOCRESET=0x6b00   # _IO('k', 0)
+
+scull_ioctl() {
+    python3 -c "import fcntl, os, sys; fd = os.open('$device', os.O_RDONLY); fcntl.ioctl(fd, $1, $2); os.close(fd)"
+}
+
+run() {
+    scull_ioctl $SCULL_IOCTQSET $1
+
+    # dd opens the device write-only, which trims it and picks up the new qset
+    echo "qset=$1: write ${size_mb} MB"
+    dd if=/dev/zero of=$device bs=$bs count=$count 2>&1 | tail -n 1
+    echo "qset=$1: read ${size_mb} MB"
+    dd if=$device of=/dev/null bs=$bs count=$count 2>&1 | tail -n 1
+}
+
+run 1
+run 1000
+
+scull_ioctl $SCULL_IOCRESET 0
+: > $device
@@ -0,0 +1,115 @@
+use kernel::prelude::*;
+use kernel::file_operations;
+use kernel::cdev::CDev;
+use kernel::sync::Arc;
+use kernel::ioctl::{_IO, _IOC_TYPE};
+use core::sync::atomic::Ordering;
+
+use crate::ioctl::SCULL_IOC_MAGIC;
+use crate::{ScullRegistry, SCULL_MAX_NR_DEVS};
+
+// /dev/scull-control, the scull take on loop-control: plain devices are added
+// and removed at runtime by index, in 0..SCULL_MAX_NR_DEVS.
+//
+// ADD takes an index, or -1 for the first unused one, and returns the index.
+// REMOVE takes an index and fails with EBUSY while the device is open or mapped.
+// GET_FREE returns an empty device nobody has open, adding one if there is none,
+// and no other GET_FREE returns it until it has been opened.
+pub(crate) const SCULL_CTL_ADD: u32 = _IO(SCULL_IOC_MAGIC, 0x80);
+pub(crate) const SCULL_CTL_REMOVE: u32 = _IO(SCULL_IOC_MAGIC, 0x81);
+pub(crate) const SCULL_CTL_GET_FREE: u32 = _IO(SCULL_IOC_MAGIC, 0x82);
+
+pub(crate) const SCULL_CONTROL_NAME: &str = "scull-control";
+
+#[derive(Debug)]
+pub(crate) struct ScullControl {
+    registry: Arc<ScullRegistry>,
+    pub(crate) cdev: CDev,
+}
+
+impl ScullControl {
+    pub(crate) fn new(registry: Arc<ScullRegistry>) -> Self {
+        ScullControl {
+            registry,
+            cdev: CDev::with_ops::<ScullControlOps>(),
+        }
+    }
+}
+
+fn add(registry: &ScullRegistry, arg: usize) -> Result<i32> {
+    let mut devs = registry.devs.lock_interruptible()?;
+    let index = match arg as i32 {
+        -1 => devs.iter().position(|slot| slot.is_none()).ok_or(Error::ENOSPC)?,
+        index if index < 0 || index as usize >= SCULL_MAX_NR_DEVS => return Err(Error::EINVAL),
+        index => index as usize,
+    };
+    if devs[index].is_some() {
+        return Err(Error::EEXIST);
+    }
+    devs[index] = Some(registry.dev_create(index)?);
+    pr_debug!("scull-control: added scull{}\n", index);
+    Ok(index as i32)
+}
+
+fn remove(registry: &ScullRegistry, arg: usize) -> Result<i32> {
+    let index = arg as i32;
+    if index < 0 || index as usize >= SCULL_MAX_NR_DEVS {
+        return Err(Error::EINVAL);
+    }
+
+    let mut devs = registry.devs.lock_interruptible()?;
+    let slot = devs[index as usize].as_ref().ok_or(Error::ENODEV)?;
+    // Open files and mappings hold a reference each, and opens only take theirs
+    // under this lock, so nobody can get to the device past this check
+    if slot.dev.refs.load(Ordering::Relaxed) > 1 {
+        return Err(Error::EBUSY);
+    }
+
+    // Dropped under the lock, so ADD can't reuse the minor before the node and
+    // debugfs entries are gone. The device goes with the last reference.
+    devs[index as usize] = None;
+    drop(devs);
+    pr_debug!("scull-control: removed scull{}\n", index);
+    Ok(0)
+}
+
+// The device handed out stays claimed until it is first opened, so that two
+// callers racing for a free device never get the same one
+fn get_free(registry: &ScullRegistry) -> Result<i32> {
+    let mut devs = registry.devs.lock_interruptible()?;
+    let idle = devs.iter().position(|slot| match slot {
+        Some(slot) => !slot.claimed && slot.dev.store.size() == 0 && slot.dev.refs.load(Ordering::Relaxed) == 1,
+        None => false,
+    });
+    let index = match idle {
+        Some(index) => index,
+        None => {
+            let index = devs.iter().position(|slot| slot.is_none()).ok_or(Error::ENOSPC)?;
+            devs[index] = Some(registry.dev_create(index)?);
+            pr_debug!("scull-control: added scull{} for GET_FREE\n", index);
+            index
+        }
+    };
+    devs[index].as_mut().unwrap().claimed = true;
+    Ok(index as i32)
+}
+
+pub(crate) struct ScullControlOps;
+
+impl file_operations::FileIoctl for ScullControlOps {
+    fn ioctl(ctx: &kernel::file_operations::FileContext, cmd: u32, arg: usize) -> Result<i32> {
+        pr_debug!("scull-control ioctl() is invoked, cmd = {:#x}\n", cmd);
+
+        if _IOC_TYPE(cmd) != SCULL_IOC_MAGIC {
+            return Err(Error::ENOTTY);
+        }
+
+        let ctl = &ctx.private_data().as_mut::<ScullControl>();
+        match cmd {
+            SCULL_CTL_ADD => add(&ctl.registry, arg),
+            SCULL_CTL_REMOVE => remove(&ctl.registry, arg),
+            SCULL_CTL_GET_FREE => get_free(&ctl.registry),
+            _ => Err(Error::ENOTTY),
+        }
+    }
+}
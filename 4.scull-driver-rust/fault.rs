@@ -0,0 +1,143 @@
+use kernel::prelude::*;
+use kernel::debugfs::{self, DebugfsBuf, Dir};
+use kernel::random::get_random_u32;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+// Fault injection knobs, one set per operation under
+// /sys/kernel/debug/scull/<device>/<op>/:
+//
+//   fail_next    the next N calls fail
+//   probability  percentage of calls failing at random
+//   errno        error returned on failure: EIO, ENOMEM, EINTR or EFAULT
+//   short        read/write only, 1 to move at most half the requested bytes
+pub(crate) const SCULL_DEBUGFS_NAME: &str = "scull";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FaultErrno {
+    Eio,
+    Enomem,
+    Eintr,
+    Efault,
+}
+
+impl FaultErrno {
+    const ALL: [(FaultErrno, &'static str); 4] = [
+        (FaultErrno::Eio, "EIO"),
+        (FaultErrno::Enomem, "ENOMEM"),
+        (FaultErrno::Eintr, "EINTR"),
+        (FaultErrno::Efault, "EFAULT"),
+    ];
+
+    fn from_u32(val: u32) -> FaultErrno {
+        Self::ALL.get(val as usize).map_or(FaultErrno::Eio, |(errno, _)| *errno)
+    }
+
+    fn name(self) -> &'static str {
+        Self::ALL[self as usize].1
+    }
+
+    fn error(self) -> Error {
+        match self {
+            FaultErrno::Eio => Error::EIO,
+            FaultErrno::Enomem => Error::ENOMEM,
+            FaultErrno::Eintr => Error::EINTR,
+            FaultErrno::Efault => Error::EFAULT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FaultKnob {
+    fail_next: AtomicU32,
+    probability: AtomicU32,
+    errno: AtomicU32, // A FaultErrno
+    short: AtomicBool,
+}
+
+impl FaultKnob {
+    fn new() -> Self {
+        FaultKnob {
+            fail_next: AtomicU32::new(0),
+            probability: AtomicU32::new(0),
+            errno: AtomicU32::new(FaultErrno::Eio as u32),
+            short: AtomicBool::new(false),
+        }
+    }
+
+    // Fails with the configured errno if this call was picked to fail
+    pub(crate) fn check(&self) -> Result {
+        let forced = self.fail_next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        let probability = self.probability.load(Ordering::Relaxed);
+        if forced || (probability > 0 && get_random_u32() % 100 < probability) {
+            let errno = FaultErrno::from_u32(self.errno.load(Ordering::Relaxed));
+            pr_debug!("scull: injecting {}\n", errno.name());
+            return Err(errno.error());
+        }
+        Ok(())
+    }
+
+    // How many of `count` bytes this call is allowed to move
+    pub(crate) fn limit(&self, count: usize) -> usize {
+        if self.short.load(Ordering::Relaxed) && count > 1 {
+            return count / 2;
+        }
+        count
+    }
+
+    fn register(&self, parent: &Dir, name: &str, with_short: bool) -> Result<Dir> {
+        let dir = parent.subdir(name)?;
+        dir.create_atomic_u32("fail_next", &self.fail_next)?;
+        dir.create_atomic_u32("probability", &self.probability)?;
+        dir.create_file("errno", self, errno_show, errno_store)?;
+        if with_short {
+            dir.create_atomic_bool("short", &self.short)?;
+        }
+        Ok(dir)
+    }
+}
+
+fn errno_show(knob: &FaultKnob, buf: &mut DebugfsBuf) -> Result<usize> {
+    let errno = FaultErrno::from_u32(knob.errno.load(Ordering::Relaxed));
+    Ok(debugfs::emit!(buf, "{}\n", errno.name()))
+}
+
+fn errno_store(knob: &FaultKnob, buf: &[u8]) -> Result<usize> {
+    let name = core::str::from_utf8(buf).map_err(|_| Error::EINVAL)?.trim();
+    let errno = FaultErrno::ALL
+        .iter()
+        .find(|(_, n)| *n == name)
+        .ok_or(Error::EINVAL)?
+        .0;
+    knob.errno.store(errno as u32, Ordering::Relaxed);
+    Ok(buf.len())
+}
+
+#[derive(Debug)]
+pub(crate) struct ScullFaults {
+    pub(crate) open: FaultKnob,
+    pub(crate) read: FaultKnob,
+    pub(crate) write: FaultKnob,
+}
+
+impl ScullFaults {
+    pub(crate) fn new() -> Self {
+        ScullFaults {
+            open: FaultKnob::new(),
+            read: FaultKnob::new(),
+            write: FaultKnob::new(),
+        }
+    }
+
+    // Devices that are never registered just never fail. The returned directory
+    // points into `self`, it must be dropped first.
+    pub(crate) fn register(&self, root: &Dir, name: fmt::Arguments<'_>) -> Result<Dir> {
+        let dir = root.subdir(name)?;
+        self.open.register(&dir, "open", false)?;
+        self.read.register(&dir, "read", true)?;
+        self.write.register(&dir, "write", true)?;
+        Ok(dir)
+    }
+}
@@ -0,0 +1,234 @@
+use kernel::prelude::*;
+use kernel::file_operations::{self, FileOpenFlag};
+use kernel::ioctl::{_IO, _IOC_NR, _IOC_TYPE, _IOR, _IOW, _IOWR};
+use kernel::security::{capable, CAP_SYS_ADMIN};
+use kernel::user_ptr::UserSlicePtr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::snapshot::{self, ScullSnapshotArg};
+use crate::{check_block_size, check_qset, ScullDev, ScullModule, SCULL_BLOCK_SIZE, SCULL_QSET, SCULL_QSET_CUR, SCULL_QUANTUM_CUR};
+
+// Same numbering as the LDD3 scull.h so the old test programs keep working.
+//
+// S means "Set" through a ptr,
+// T means "Tell" directly with the argument value
+// G means "Get": reply by setting through a pointer
+// Q means "Query": response is on the return value
+// X means "eXchange": switch G and S atomically
+// H means "sHift": switch T and Q atomically
+pub(crate) const SCULL_IOC_MAGIC: u32 = b'k' as u32;
+
+pub(crate) const SCULL_IOCRESET: u32 = _IO(SCULL_IOC_MAGIC, 0);
+
+pub(crate) const SCULL_IOCSQUANTUM: u32 = _IOW::<i32>(SCULL_IOC_MAGIC, 1);
+pub(crate) const SCULL_IOCSQSET: u32 = _IOW::<i32>(SCULL_IOC_MAGIC, 2);
+pub(crate) const SCULL_IOCTQUANTUM: u32 = _IO(SCULL_IOC_MAGIC, 3);
+pub(crate) const SCULL_IOCTQSET: u32 = _IO(SCULL_IOC_MAGIC, 4);
+pub(crate) const SCULL_IOCGQUANTUM: u32 = _IOR::<i32>(SCULL_IOC_MAGIC, 5);
+pub(crate) const SCULL_IOCGQSET: u32 = _IOR::<i32>(SCULL_IOC_MAGIC, 6);
+pub(crate) const SCULL_IOCQQUANTUM: u32 = _IO(SCULL_IOC_MAGIC, 7);
+pub(crate) const SCULL_IOCQQSET: u32 = _IO(SCULL_IOC_MAGIC, 8);
+pub(crate) const SCULL_IOCXQUANTUM: u32 = _IOWR::<i32>(SCULL_IOC_MAGIC, 9);
+pub(crate) const SCULL_IOCXQSET: u32 = _IOWR::<i32>(SCULL_IOC_MAGIC, 10);
+pub(crate) const SCULL_IOCHQUANTUM: u32 = _IO(SCULL_IOC_MAGIC, 11);
+pub(crate) const SCULL_IOCHQSET: u32 = _IO(SCULL_IOC_MAGIC, 12);
+
+// Save and load the whole device, see snapshot.rs for the blob format
+pub(crate) const SCULL_IOCGSNAPSHOT: u32 = _IOWR::<ScullSnapshotArg>(SCULL_IOC_MAGIC, 13);
+pub(crate) const SCULL_IOCRESTORE: u32 = _IOW::<ScullSnapshotArg>(SCULL_IOC_MAGIC, 14);
+
+// Check every block against its checksum, and flip a bit to test that it works
+pub(crate) const SCULL_IOCSCRUB: u32 = _IOWR::<ScullScrubArg>(SCULL_IOC_MAGIC, 15);
+pub(crate) const SCULL_IOCFLIPBIT: u32 = _IO(SCULL_IOC_MAGIC, 16);
+
+// Set the size directly with the argument value, ftruncate doesn't reach char devices
+pub(crate) const SCULL_IOCTRUNCATE: u32 = _IO(SCULL_IOC_MAGIC, 17);
+
+pub(crate) const SCULL_IOC_MAXNR: u32 = 17;
+
+// Argument of SCULL_IOCSCRUB
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ScullScrubArg {
+    pub(crate) addr: u64, // User array of u64 receiving the corrupt block indexes
+    pub(crate) len: u64,  // Number of entries in it
+}
+
+// Values are checked like the module parameters, see check_block_size and check_qset
+fn check_value(val: isize, check: fn(usize) -> Result) -> Result<usize> {
+    if val <= 0 {
+        return Err(Error::EINVAL);
+    }
+    check(val as usize)?;
+    Ok(val as usize)
+}
+
+fn read_user_int(arg: usize, check: fn(usize) -> Result) -> Result<usize> {
+    let mut val = [0u8; 4];
+    UserSlicePtr::new(arg, val.len()).reader().read_slice(&mut val)?;
+    check_value(i32::from_ne_bytes(val) as isize, check)
+}
+
+fn write_user_int(arg: usize, val: usize) -> Result {
+    UserSlicePtr::new(arg, 4).writer().write_slice(&(val as i32).to_ne_bytes())
+}
+
+// Returns the number of corrupt blocks, the first `len` of them are listed in the array
+fn scrub(dev: &mut ScullDev, arg: usize) -> Result<i32> {
+    let mut raw = [0u8; 16];
+    UserSlicePtr::new(arg, raw.len()).reader().read_slice(&mut raw)?;
+    let addr = u64::from_ne_bytes(raw[0..8].try_into().unwrap()) as usize;
+    let len = u64::from_ne_bytes(raw[8..16].try_into().unwrap()) as usize;
+    let mut writer = UserSlicePtr::new(addr, len.checked_mul(8).ok_or(Error::EINVAL)?).writer();
+
+    // Exclusive, blocks faulted into a mapping since the last scrub get their checksum here.
+    // The corrupt indexes are gathered first and copied out with the semaphore dropped,
+    // the user array may fault on a mapping of this device.
+    dev.sem.down_write_interruptible()?;
+    let mut found = match Vec::try_with_capacity(len.min(dev.store.block_count())) {
+        Ok(found) => found,
+        Err(e) => {
+            dev.sem.up_write();
+            return Err(e.into());
+        }
+    };
+    let mapped = dev.vmas.load(Ordering::Relaxed) > 0;
+    let mut corrupt = 0;
+    for (index, pblock) in dev.store.blocks_mut() {
+        if !mapped && pblock.stale.load(Ordering::Relaxed) {
+            pblock.refresh();
+        }
+        if pblock.verify().is_err() {
+            if corrupt < len {
+                let _ = found.try_push(index as u64); // Can't fail, the capacity was taken above
+            }
+            corrupt += 1;
+        }
+    }
+    let total = dev.store.block_count();
+    dev.sem.up_write();
+
+    for index in found {
+        writer.write_slice(&index.to_ne_bytes())?;
+    }
+
+    pr_debug!("scrub: {} corrupt blocks out of {}\n", corrupt, total);
+    Ok(corrupt as i32)
+}
+
+// `arg` is the bit number counted from the start of the device
+fn flip_bit(dev: &mut ScullDev, arg: usize) -> Result<i32> {
+    check_admin()?;
+
+    let offset = arg / 8;
+    dev.sem.down_write_interruptible()?;
+    if dev.vmas.load(Ordering::Relaxed) > 0 {
+        dev.sem.up_write();
+        return Err(Error::EBUSY); // The mapping could change the block under the new checksum
+    }
+    let quantum = dev.store.quantum();
+    let ret = match dev.store.block_at_mut(offset) {
+        Some(pblock) => {
+            pblock.flip_bit(offset % quantum, (arg % 8) as u8);
+            Ok(0)
+        }
+        None => Err(Error::EINVAL), // Nothing stored there
+    };
+    dev.sem.up_write();
+    ret
+}
+
+// `arg` is the new size, data past it is dropped and a grown device reads back zeros
+fn truncate(dev: &mut ScullDev, arg: usize) -> Result<i32> {
+    dev.sem.down_write_interruptible()?;
+    let ret = dev.truncate(arg);
+    dev.sem.up_write();
+    ret.map(|()| 0)
+}
+
+fn check_admin() -> Result {
+    if !capable(CAP_SYS_ADMIN) {
+        return Err(Error::EPERM);
+    }
+    Ok(())
+}
+
+// One value is handled the same way for quantum and qset, `op` is the
+// command number with the quantum/qset bit folded away.
+fn geometry_ioctl(cur: &AtomicUsize, check: fn(usize) -> Result, op: u32, arg: usize) -> Result<i32> {
+    match op {
+        1 => {
+            check_admin()?;
+            cur.store(read_user_int(arg, check)?, Ordering::Relaxed);
+            Ok(0)
+        }
+        3 => {
+            check_admin()?;
+            cur.store(check_value(arg as isize, check)?, Ordering::Relaxed);
+            Ok(0)
+        }
+        5 => {
+            write_user_int(arg, cur.load(Ordering::Relaxed))?;
+            Ok(0)
+        }
+        7 => Ok(cur.load(Ordering::Relaxed) as i32),
+        9 => {
+            check_admin()?;
+            let new = read_user_int(arg, check)?;
+            let old = cur.swap(new, Ordering::Relaxed);
+            write_user_int(arg, old)?;
+            Ok(0)
+        }
+        11 => {
+            check_admin()?;
+            let new = check_value(arg as isize, check)?;
+            Ok(cur.swap(new, Ordering::Relaxed) as i32)
+        }
+        _ => Err(Error::ENOTTY),
+    }
+}
+
+impl file_operations::FileIoctl for ScullModule {
+    fn ioctl(ctx: &kernel::file_operations::FileContext, cmd: u32, arg: usize) -> Result<i32> {
+        pr_debug!("ioctl() is invoked, cmd = {:#x}\n", cmd);
+
+        // Don't decode wrong cmds, better returning ENOTTY than EFAULT
+        if _IOC_TYPE(cmd) != SCULL_IOC_MAGIC || _IOC_NR(cmd) > SCULL_IOC_MAXNR {
+            return Err(Error::ENOTTY);
+        }
+
+        match cmd {
+            SCULL_IOCRESET => {
+                SCULL_QUANTUM_CUR.store(SCULL_BLOCK_SIZE, Ordering::Relaxed);
+                SCULL_QSET_CUR.store(SCULL_QSET, Ordering::Relaxed);
+                Ok(0)
+            }
+            SCULL_IOCSQUANTUM | SCULL_IOCTQUANTUM | SCULL_IOCGQUANTUM
+            | SCULL_IOCQQUANTUM | SCULL_IOCXQUANTUM | SCULL_IOCHQUANTUM => {
+                geometry_ioctl(&SCULL_QUANTUM_CUR, check_block_size, _IOC_NR(cmd), arg)
+            }
+            SCULL_IOCSQSET | SCULL_IOCTQSET | SCULL_IOCGQSET
+            | SCULL_IOCQQSET | SCULL_IOCXQSET | SCULL_IOCHQSET => {
+                // Even numbers are the qset twin of the quantum command before it
+                geometry_ioctl(&SCULL_QSET_CUR, check_qset, _IOC_NR(cmd) - 1, arg)
+            }
+            SCULL_IOCGSNAPSHOT => snapshot::snapshot(&ctx.private_data().as_mut::<ScullDev>(), arg),
+            SCULL_IOCRESTORE => {
+                // Restoring rewrites the device, so it takes a writable file
+                if !ctx.flags().contains(FileOpenFlag::WRITE) {
+                    return Err(Error::EBADF);
+                }
+                snapshot::restore(&mut ctx.private_data().as_mut::<ScullDev>(), arg)
+            }
+            SCULL_IOCSCRUB => scrub(&mut ctx.private_data().as_mut::<ScullDev>(), arg),
+            SCULL_IOCFLIPBIT => flip_bit(&mut ctx.private_data().as_mut::<ScullDev>(), arg),
+            SCULL_IOCTRUNCATE => {
+                if !ctx.flags().contains(FileOpenFlag::WRITE) {
+                    return Err(Error::EBADF);
+                }
+                truncate(&mut ctx.private_data().as_mut::<ScullDev>(), arg)
+            }
+            _ => Err(Error::ENOTTY),
+        }
+    }
+}
@@ -3,23 +3,19 @@ module="scull"
 device="scull"
 mode="666"
 group=0
+nodes="/dev/${device}[0-9]* /dev/${device}pipe[0-3] /dev/${device}single /dev/${device}uid /dev/${device}wuid /dev/${device}priv /dev/${device}c[0-3]"
 
 function load() {
     insmod ./$module.ko $* || exit 1
 
-    rm -f /dev/${device}[0-2]
+    # The nodes are created by udev from /sys/class/scull, wait for them
+    udevadm settle
 
-    major=$(awk -v device="$device" '$2==device {print $1}' /proc/devices)
-    mknod /dev/${device}0 c $major 0
-    mknod /dev/${device}1 c $major 1
-    mknod /dev/${device}2 c $major 2
-
-    chgrp $group /dev/$device[0-2]
-    chmod $mode /dev/$device[0-2]
+    chgrp $group $nodes
+    chmod $mode $nodes
 }
 
 function unload() {
-    rm -f /dev/${device}[0-2]
     rmmod $module || exit 1
 }
 
@@ -38,4 +34,4 @@ case $arg in
         echo "Default is load"
         exit 1
         ;;
-esac
\ No newline at end of file
+esac
@@ -4,14 +4,104 @@
 extern crate kernel;
 
 use kernel::prelude::*;
-use kernel::file_operations;
+use kernel::file_operations::{self, FileOpenFlag, IocbFlag, Kiocb, PollFlags, PollTable, SeekFrom};
+use kernel::iov_iter::IovIter;
+use kernel::pipe::PipeInodeInfo;
 use kernel::cdev::CDev;
+use kernel::device::{Class, Device};
+use kernel::types::DevT;
+use kernel::sync::{Arc, RwSemaphore};
 use kernel::mutex::Mutex;
-use kernel::slab::Slab;
-use kernel::list::{ListHead, ListNode};
+use kernel::param::{self, Param};
+use kernel::pages::PAGE_SIZE;
+use kernel::slab::KmemCache;
+use kernel::procfs;
+use kernel::debugfs;
+use kernel::waitqueue::WaitQueue;
+use kernel::fasync::{FasyncQueue, POLL_IN};
+use kernel::signal::SIGIO;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
 
-const SCULL_NR_DEVS: usize = 4; // Number of devices
-const SCULL_BLOCK_SIZE: usize = 512; // Block size for each device
+// The storage core is a plain no_std crate, so that it can be tested on the host
+#[path = "scull-core/src/lib.rs"]
+#[allow(unused_attributes)]
+mod scull_core;
+
+mod access;
+mod backend;
+mod control;
+mod fault;
+mod ioctl;
+mod mmap;
+mod pipe;
+mod private;
+mod proc;
+mod snapshot;
+mod shrinker;
+mod sysfs;
+
+use access::{AccessPolicy, ScullAccess, SCULL_A_NR_DEVS};
+use scull_core::{Block, Geometry, Store};
+use backend::{register_alloc_stats, ScullBackend, ScullMem, SCULL_BACKEND_NAMES, SCULLC_CACHE_NAME, SCULLC_NR_DEVS};
+use control::{ScullControl, SCULL_CONTROL_NAME};
+use fault::{ScullFaults, SCULL_DEBUGFS_NAME};
+use pipe::{ScullPipe, SCULL_P_NR_DEVS};
+use private::ScullPriv;
+use proc::{ScullSeqOps, SCULL_PROC_NAME};
+use shrinker::ScullShrinker;
+use sysfs::{ScullAttrs, ScullClassAttrs, SCULL_CLASS_NAME};
+
+const SCULL_MAJOR: u32 = 0; // Dynamic major by default
+const SCULL_NR_DEVS: usize = 4; // Default number of devices at load time
+const SCULL_BLOCK_SIZE: usize = PAGE_SIZE; // Default block size, always a multiple of PAGE_SIZE
+const SCULL_QSET: usize = 1000; // Default number of blocks in a qset
+
+// Limits checked on the module parameters at load time
+const SCULL_MAX_MAJOR: u32 = 4095;
+const SCULL_MAX_MINOR: u32 = (1 << 20) - 1;
+const SCULL_MAX_NR_DEVS: usize = 256;
+const SCULL_MAX_BLOCK_SIZE: usize = 1 << 20;
+const SCULL_MAX_QSET: usize = 1 << 16;
+
+// First scullc minor, relative to scull_minor, see ScullModule::total_devs
+const SCULLC_FIRST: usize = SCULL_MAX_NR_DEVS + SCULL_P_NR_DEVS + SCULL_A_NR_DEVS + 2;
+
+// Block sizes and qset lengths are checked the same way at load time, through
+// ioctl and through sysfs. Blocks are built from whole pages, see ScullBlock.
+fn check_block_size(quantum: usize) -> Result {
+    if quantum == 0 || quantum > SCULL_MAX_BLOCK_SIZE || quantum % PAGE_SIZE != 0 {
+        return Err(Error::EINVAL);
+    }
+    Ok(())
+}
+
+fn check_qset(qset: usize) -> Result {
+    if qset == 0 || qset > SCULL_MAX_QSET {
+        return Err(Error::EINVAL);
+    }
+    Ok(())
+}
+
+// Geometry picked up by a device on its next trim, changed through ioctl
+static SCULL_QUANTUM_CUR: AtomicUsize = AtomicUsize::new(SCULL_BLOCK_SIZE);
+static SCULL_QSET_CUR: AtomicUsize = AtomicUsize::new(SCULL_QSET);
+
+// Memory caps in bytes, 0 means no limit. The per-device one is the default
+// for new devices and can be changed per device through sysfs.
+static SCULL_DEV_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static SCULL_MEM_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static SCULL_MEM_USED: AtomicUsize = AtomicUsize::new(0); // Block and qset memory of all devices
+static SCULL_RECLAIMED: AtomicUsize = AtomicUsize::new(0); // Bytes dropped by the shrinker so far
+
+// A qset that couldn't be allocated, see scull-core
+impl From<scull_core::AllocError> for Error {
+    fn from(_: scull_core::AllocError) -> Error {
+        Error::ENOMEM
+    }
+}
 
 module! {
     type: ScullModule,
@@ -21,69 +111,768 @@ module! {
     license: b"GPL",
 }
 
+// Blocks get their memory from the device's backend, see backend.rs
 #[derive(Debug)]
 struct ScullBlock {
-    data: [u8; SCULL_BLOCK_SIZE],
+    mem: ScullMem,
     offset: usize,
-    block_list: ListNode,
+    crc: u32, // CRC32 of the whole block, taken again by every write
+    stale: AtomicBool, // Faulted into a mapping, the crc may not match until the next scrub
+}
+
+impl ScullBlock {
+    fn alloc(backend: &ScullBackend, quantum: usize) -> Result<Self> {
+        let mut block = ScullBlock {
+            mem: backend.alloc(quantum)?,
+            offset: 0,
+            crc: 0,
+            stale: AtomicBool::new(false),
+        };
+        block.update();
+        Ok(block)
+    }
+
+    // Copy `len` bytes at `offset` to the iterator, stops short on a bad user buffer
+    fn copy_to_iter(&self, offset: usize, len: usize, iter: &mut IovIter) -> usize {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let count = self.mem.chunk(pos).min(len - done);
+            let copied = self.mem.copy_to_iter(pos, count, iter);
+            done += copied;
+            if copied < count {
+                break;
+            }
+        }
+        done
+    }
+
+    fn copy_from_iter(&mut self, offset: usize, len: usize, iter: &mut IovIter) -> usize {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done;
+            let count = self.mem.chunk(pos).min(len - done);
+            let copied = self.mem.copy_from_iter(pos, count, iter);
+            done += copied;
+            if copied < count {
+                break;
+            }
+        }
+        self.update();
+        done
+    }
+
+    fn checksum(&self) -> u32 {
+        self.mem.checksum()
+    }
+
+    // Take the checksum again after a write. A stale block stays stale, the
+    // mapping may still change it.
+    fn update(&mut self) {
+        self.crc = self.checksum();
+    }
+
+    // Take the checksum of a block that is no longer mapped
+    fn refresh(&mut self) {
+        self.crc = self.checksum();
+        *self.stale.get_mut() = false;
+    }
+
+    // The contents may change behind our back, through a mapping
+    fn invalidate(&self) {
+        self.stale.store(true, Ordering::Relaxed);
+    }
+
+    // Stale blocks have nothing to be checked against, they pass
+    fn verify(&self) -> Result {
+        if self.stale.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        if self.checksum() != self.crc {
+            return Err(Error::EIO);
+        }
+        Ok(())
+    }
+
+    // Debug only: flip a bit behind the checksum's back
+    fn flip_bit(&mut self, offset: usize, bit: u8) {
+        self.refresh();
+        let mut byte = [0u8; 1];
+        self.mem.read_slice(offset, &mut byte);
+        byte[0] ^= 1 << bit;
+        self.mem.write_slice(offset, &byte);
+    }
+}
+
+impl Block for ScullBlock {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let count = self.mem.chunk(pos).min(buf.len() - done);
+            self.mem.read_slice(pos, &mut buf[done..done + count]);
+            done += count;
+        }
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) {
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done;
+            let count = self.mem.chunk(pos).min(buf.len() - done);
+            self.mem.write_slice(pos, &buf[done..done + count]);
+            done += count;
+        }
+        self.update();
+    }
+
+    fn zero_from(&mut self, offset: usize) {
+        let mut pos = offset;
+        while pos < self.mem.len() {
+            let count = self.mem.chunk(pos);
+            self.mem.zero_range(pos, count);
+            pos += count;
+        }
+        self.offset = self.offset.min(offset);
+        self.update();
+    }
 }
 
 #[derive(Debug)]
 struct ScullDev {
-    mutex: Mutex<()>,
-    block_counter: usize,
-    block_list: ListHead,
-    cdev: CDev,
+    sem: RwSemaphore<()>, // Readers share it, writes, trims and truncates are exclusive
+    index: Mutex<()>, // Also held to change the blocks, size or geometry, the mmap fault path only takes this
+    store: Store<ScullBlock>, // Blocks, size and geometry, see scull-core
+    quantum_pinned: bool, // Block size set through sysfs or by the backend, trim leaves it alone
+    mem_limit: usize, // Cap on block and qset memory for this device, 0 for none
+    cache: bool, // Contents may be dropped under memory pressure, see shrinker.rs
+    reclaimed: usize, // Bytes dropped by the shrinker so far
+    changes: usize, // Bumped by every write, trim, truncate and reclaim, under the semaphore
+    refs: AtomicUsize, // Held by the owner and by every open file and mapping, see ScullRef
+    open_count: AtomicUsize,
+    waitq: WaitQueue, // Pollers waiting for the contents to change
+    async_queue: FasyncQueue, // Readers asking for SIGIO
+    vmas: AtomicUsize, // Active mappings, the device can't be trimmed while mapped. Atomic, see mmap.rs
+    backend: ScullBackend, // After `store`, blocks go back to a cache before it can go
+    faults: ScullFaults, // Injected failures, set through debugfs
 }
 
 impl ScullDev {
-    fn new() -> Self {
-        ScullDev {
-            mutex: Mutex::new(()),
-            block_counter: 0,
-            block_list: ListHead::new(),
-            cdev: CDev::new(),
+    fn new() -> Result<Self> {
+        Self::with_backend(ScullBackend::Page)
+    }
+
+    fn with_backend(backend: ScullBackend) -> Result<Self> {
+        let fixed = backend.fixed_quantum();
+        let quantum = fixed.unwrap_or_else(|| SCULL_QUANTUM_CUR.load(Ordering::Relaxed));
+        Ok(ScullDev {
+            sem: RwSemaphore::new(()),
+            index: Mutex::new(()),
+            store: Store::new(Self::geometry(quantum, SCULL_QSET_CUR.load(Ordering::Relaxed))?),
+            quantum_pinned: fixed.is_some(),
+            mem_limit: SCULL_DEV_LIMIT.load(Ordering::Relaxed),
+            cache: false,
+            reclaimed: 0,
+            changes: 0,
+            refs: AtomicUsize::new(1),
+            open_count: AtomicUsize::new(0),
+            waitq: WaitQueue::new(),
+            async_queue: FasyncQueue::new(),
+            vmas: AtomicUsize::new(0),
+            backend,
+            faults: ScullFaults::new(),
+        })
+    }
+
+    // Another reference for an open file or a mapping, dropped with `put`
+    fn get(&self) -> *mut ScullDev {
+        self.refs.fetch_add(1, Ordering::Relaxed);
+        self as *const ScullDev as *mut ScullDev
+    }
+
+    // Drop a reference, the last one frees the device. Only devices allocated by
+    // ScullRef can get there, the others keep their owner's reference until they go.
+    //
+    // SAFETY: `dev` holds a reference and is not used past this call.
+    unsafe fn put(dev: *mut ScullDev) {
+        if unsafe { (*dev).refs.fetch_sub(1, Ordering::Release) } == 1 {
+            fence(Ordering::Acquire);
+            drop(unsafe { Box::from_raw(dev) });
+        }
+    }
+
+    // What every flavour of the device does on open, once the file points to it
+    fn open(&mut self, ctx: &kernel::file_operations::FileContext) -> Result {
+        self.open_count.fetch_add(1, Ordering::Relaxed);
+        let ret = (|| -> Result {
+            self.faults.open.check()?;
+
+            // Trim the device when opened write-only, unless it is being appended to
+            if ctx.flags().contains(FileOpenFlag::WRITE_ONLY) && !ctx.flags().contains(FileOpenFlag::APPEND) {
+                self.sem.down_write_interruptible()?;
+                let ret = self.trim();
+                self.sem.up_write();
+                ret?;
+            }
+            Ok(())
+        })();
+        if ret.is_err() {
+            self.open_count.fetch_sub(1, Ordering::Relaxed);
         }
+        ret
+    }
+
+    fn release(&mut self, ctx: &kernel::file_operations::FileContext) -> Result {
+        // Remove this filp from the asynchronously notified filp's
+        let ret = self.async_queue.helper(-1, ctx, false);
+        self.open_count.fetch_sub(1, Ordering::Relaxed);
+        ret
     }
 
-    fn trim(&mut self) {
+    fn geometry(quantum: usize, qset: usize) -> Result<Geometry> {
+        check_block_size(quantum)?;
+        check_qset(qset)?;
+        Geometry::new(quantum, qset).ok_or(Error::EINVAL)
+    }
+
+    // Drop the contents and switch to `geometry`
+    fn reset(&mut self, geometry: Geometry) -> Result {
+        if self.vmas.load(Ordering::Relaxed) > 0 {
+            return Err(Error::EBUSY); // Don't trim: there are active mappings
+        }
+        let index = self.index.lock();
+        Self::uncharge(self.mem_used());
+        self.store.reset(geometry);
+        drop(index);
+        self.changes += 1;
+        self.waitq.wake_up_interruptible();
+        Ok(())
+    }
+
+    fn trim(&mut self) -> Result {
         pr_debug!("scull_trim() is invoked\n");
-        let mut current = self.block_list.head();
-        while let Some(node) = current {
-            let block = unsafe { node.as_mut::<ScullBlock>() };
-            current = node.next();
-            // Remove and clean up the block
-            self.block_list.remove(node);
-            unsafe { kernel::slab::dealloc(block) };
+        let quantum = if self.quantum_pinned {
+            self.store.quantum()
+        } else {
+            SCULL_QUANTUM_CUR.load(Ordering::Relaxed)
+        };
+        self.reset(Self::geometry(quantum, SCULL_QSET_CUR.load(Ordering::Relaxed))?)
+    }
+
+    // Shrink or grow the device to `new_size` without touching the data before it
+    fn truncate(&mut self, new_size: usize) -> Result {
+        pr_debug!("scull_truncate() is invoked, {} -> {}\n", self.store.size(), new_size);
+        if new_size < self.store.size() && self.vmas.load(Ordering::Relaxed) > 0 {
+            return Err(Error::EBUSY); // Mapped pages could go away under the mapping
+        }
+        let used = self.mem_used();
+        let index = self.index.lock();
+        self.store.truncate(new_size);
+        drop(index);
+        self.changes += 1;
+        Self::uncharge(used - self.mem_used());
+        self.waitq.wake_up_interruptible();
+        Ok(())
+    }
+
+    // RWF_NOWAIT and io_uring callers would rather get EAGAIN than sleep on the lock
+    fn lock_read(&self, kiocb: &Kiocb) -> Result {
+        if kiocb.flags().contains(IocbFlag::NOWAIT) {
+            if !self.sem.down_read_trylock() {
+                return Err(Error::EAGAIN);
+            }
+            return Ok(());
+        }
+        self.sem.down_read_interruptible()
+    }
+
+    fn lock_write(&self, kiocb: &Kiocb) -> Result {
+        if kiocb.flags().contains(IocbFlag::NOWAIT) {
+            if !self.sem.down_write_trylock() {
+                return Err(Error::EAGAIN);
+            }
+            return Ok(());
+        }
+        self.sem.down_write_interruptible()
+    }
+
+    // Block sizes other than the backend's own are refused
+    fn check_quantum(&self, quantum: usize) -> Result {
+        match self.backend.fixed_quantum() {
+            Some(fixed) if fixed != quantum => Err(Error::EINVAL),
+            _ => Ok(()),
+        }
+    }
+
+    // Free up to `bytes` worth of blocks, leaving holes that read back as zeros.
+    // The size doesn't change. Returns how much was freed.
+    fn reclaim(&mut self, bytes: usize) -> usize {
+        if self.vmas.load(Ordering::Relaxed) > 0 {
+            return 0; // Mapped pages can't go away
+        }
+
+        let quantum = self.store.quantum();
+        let index = self.index.lock();
+        let freed = self.store.reclaim(bytes.div_ceil(quantum)) * quantum;
+        drop(index);
+        Self::uncharge(freed);
+        self.changes += 1;
+        self.reclaimed += freed;
+        SCULL_RECLAIMED.fetch_add(freed, Ordering::Relaxed);
+        freed
+    }
+
+    fn mem_used(&self) -> usize {
+        self.store.mem_used() + self.store.qset_mem()
+    }
+
+    // Account `bytes` of new block or qset memory against the device and module caps
+    fn charge(dev_used: usize, dev_limit: usize, bytes: usize) -> Result {
+        if dev_limit != 0 && dev_used + bytes > dev_limit {
+            return Err(Error::ENOSPC);
+        }
+        let limit = SCULL_MEM_LIMIT.load(Ordering::Relaxed);
+        let used = SCULL_MEM_USED.fetch_add(bytes, Ordering::Relaxed);
+        if limit != 0 && used + bytes > limit {
+            Self::uncharge(bytes);
+            return Err(Error::ENOSPC);
+        }
+        Ok(())
+    }
+
+    fn uncharge(bytes: usize) {
+        SCULL_MEM_USED.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // Allocator for the store, charging every block and qset to the device and the module
+    fn allocator(&self) -> ScullAlloc {
+        ScullAlloc {
+            backend: self.backend.clone(),
+            quantum: self.store.quantum(),
+            used: self.mem_used(),
+            limit: self.mem_limit,
+        }
+    }
+}
+
+struct ScullAlloc {
+    backend: ScullBackend,
+    quantum: usize,
+    used: usize, // Device memory so far, for the per-device cap
+    limit: usize,
+}
+
+impl ScullAlloc {
+    fn charge(&mut self, bytes: usize) -> Result {
+        ScullDev::charge(self.used, self.limit, bytes)?;
+        self.used += bytes;
+        Ok(())
+    }
+}
+
+impl scull_core::Alloc<ScullBlock> for ScullAlloc {
+    type Error = Error;
+
+    fn block(&mut self) -> Result<ScullBlock> {
+        self.charge(self.quantum)?;
+        ScullBlock::alloc(&self.backend, self.quantum).map_err(|e| {
+            ScullDev::uncharge(self.quantum);
+            self.used -= self.quantum;
+            e
+        })
+    }
+
+    fn qset(&mut self, bytes: usize) -> Result {
+        self.charge(bytes)
+    }
+}
+
+impl Drop for ScullDev {
+    fn drop(&mut self) {
+        Self::uncharge(self.mem_used());
+    }
+}
+
+// The owning reference to a device that files can outlive, freed by whoever drops
+// the last one. Not an Arc: the shrinker, sysfs and the file ops all need the
+// device mutably, so the count lives in the device and the rest is left to its locks.
+struct ScullRef(NonNull<ScullDev>);
+
+impl ScullRef {
+    fn new(dev: ScullDev) -> Result<Self> {
+        Ok(ScullRef(NonNull::from(Box::leak(Box::try_new(dev)?))))
+    }
+}
+
+impl Deref for ScullRef {
+    type Target = ScullDev;
+
+    fn deref(&self) -> &ScullDev {
+        // SAFETY: our reference keeps the device alive
+        unsafe { self.0.as_ref() }
+    }
+}
+
+impl DerefMut for ScullRef {
+    fn deref_mut(&mut self) -> &mut ScullDev {
+        // SAFETY: as above, and the device is only reached through its locks
+        unsafe { self.0.as_mut() }
+    }
+}
+
+impl Drop for ScullRef {
+    fn drop(&mut self) {
+        // SAFETY: this is our reference, and we're done with it
+        unsafe { ScullDev::put(self.0.as_ptr()) };
+    }
+}
+
+// A registered device. `device` and `faults` point into `dev`, so they go first
+struct ScullSlot {
+    device: Device, // Entry under /sys/class/scull
+    faults: debugfs::Dir,
+    dev: ScullRef,
+    claimed: bool, // Handed out by GET_FREE and not opened yet, see control.rs
+}
+
+// What scull-control and the shrinker reach once init is done. The module value
+// is moved out of init, so this lives behind an Arc instead of being pointed to in place.
+struct ScullRegistry {
+    devs: Mutex<Vec<Option<ScullSlot>>>, // One slot per possible plain device, see control.rs
+    scullc_devs: Mutex<[Option<ScullSlot>; SCULLC_NR_DEVS]>,
+    backends: Vec<ScullBackend>, // From scull_backends, scull for the devices past its end
+    debugfs: debugfs::Dir, // /sys/kernel/debug/scull
+    class: Class,
+    major: u32,
+    minor: u32,
+}
+
+impl ScullRegistry {
+    // Set up a device with its node, sysfs and debugfs entries. On error what was
+    // set up so far is dropped in reverse order, the device itself last.
+    fn dev_setup(&self, minor: usize, name: fmt::Arguments<'_>, backend: ScullBackend) -> Result<ScullSlot> {
+        let dev = ScullRef::new(ScullDev::with_backend(backend)?)?;
+        let devno = kernel::MKDEV(self.major, self.minor + minor as u32);
+        let device = self.class.device_create_with_groups::<ScullAttrs>(devno, &*dev, name)?;
+        let faults = dev.faults.register(&self.debugfs, name)?;
+        Ok(ScullSlot { device, faults, dev, claimed: false })
+    }
+
+    // Plain device `index`, at load time or through scull-control
+    fn dev_create(&self, index: usize) -> Result<ScullSlot> {
+        let backend = self.backends.get(index).cloned().unwrap_or(ScullBackend::Page);
+        self.dev_setup(index, fmt!("scull{}", index), backend)
+    }
+
+    // A reference to the device behind `minor` for a file being opened. Taken under
+    // the list lock, so scull-control sees it before it can remove the device.
+    fn dev_get(&self, minor: usize) -> Result<*mut ScullDev> {
+        let open = |slot: &mut Option<ScullSlot>| -> Result<*mut ScullDev> {
+            let slot = slot.as_mut().ok_or(Error::ENODEV)?;
+            slot.claimed = false;
+            Ok(slot.dev.get())
+        };
+        if minor < SCULL_MAX_NR_DEVS {
+            open(&mut self.devs.lock_interruptible()?[minor])
+        } else {
+            open(&mut self.scullc_devs.lock_interruptible()?[minor - SCULLC_FIRST])
         }
-        self.block_counter = 0;
+    }
+}
+
+// The node of every plain or scullc device, added once for the whole range. Files
+// find their device by minor on open, so a node outlives the devices behind it.
+#[derive(Debug)]
+struct ScullCdev {
+    registry: Arc<ScullRegistry>,
+    cdev: CDev,
+}
+
+impl ScullCdev {
+    fn new(registry: Arc<ScullRegistry>) -> Self {
+        ScullCdev { registry, cdev: CDev::new() }
     }
 }
 
 struct ScullModule {
-    devs: [Option<ScullDev>; SCULL_NR_DEVS],
+    scull_major: Param<u32>,
+    scull_minor: Param<u32>,
+    scull_nr_devs: Param<usize>,
+    scull_quantum: Param<usize>,
+    scull_qset: Param<usize>,
+    scull_dev_limit: Param<usize>,
+    scull_mem_limit: Param<usize>,
+    scull_backends: Param<String>, // Comma separated, one per plain device, scull for the rest
+    scullp_order: Param<u32>,
+    registry: Option<Arc<ScullRegistry>>, // Plain devices, and what it takes to add more
+    scull_cdev: Option<ScullCdev>, // Plain devices, all SCULL_MAX_NR_DEVS minors
+    scullc_cdev: Option<ScullCdev>,
+    pipes: [Option<ScullPipe>; SCULL_P_NR_DEVS],
+    access_devs: [Option<ScullAccess>; SCULL_A_NR_DEVS],
+    priv_dev: Option<ScullPriv>,
+    control: Option<ScullControl>,
+    shrinker: Option<ScullShrinker>,
+    nodes: Vec<Device>, // Class devices of the nodes that have no sysfs attributes
     major: u32,
     minor: u32,
 }
 
+impl ScullModule {
+    fn new() -> ScullModule {
+        ScullModule {
+            scull_major: Param::new(SCULL_MAJOR, param::Flags::READ_ONLY),
+            scull_minor: Param::new(0, param::Flags::READ_ONLY),
+            scull_nr_devs: Param::new(SCULL_NR_DEVS, param::Flags::READ_ONLY),
+            scull_quantum: Param::new(SCULL_BLOCK_SIZE, param::Flags::READ_ONLY),
+            scull_qset: Param::new(SCULL_QSET, param::Flags::READ_ONLY),
+            scull_dev_limit: Param::new(0, param::Flags::READ_ONLY),
+            scull_mem_limit: Param::new(0, param::Flags::READ_ONLY),
+            scull_backends: Param::new(String::new(), param::Flags::READ_ONLY),
+            scullp_order: Param::new(0, param::Flags::READ_ONLY),
+            registry: None,
+            scull_cdev: None,
+            scullc_cdev: None,
+            pipes: Default::default(),
+            access_devs: Default::default(),
+            priv_dev: None,
+            control: None,
+            shrinker: None,
+            nodes: Vec::new(),
+            major: 0,
+            minor: 0,
+        }
+    }
+
+    // Minors are handed out as plain devices, then pipes, then access-controlled
+    // ones, then scullpriv, scull-control and the scullc devices. Every possible
+    // plain device has its minor reserved, so that scull-control can add them later.
+    fn total_devs(&self) -> usize {
+        SCULLC_FIRST + SCULLC_NR_DEVS
+    }
+
+    // Names were checked by check_params
+    fn backends(&self) -> Result<Vec<ScullBackend>> {
+        let mut backends = Vec::new();
+        if !self.scull_backends.is_empty() {
+            for name in self.scull_backends.split(',') {
+                backends.try_push(ScullBackend::from_name(name.trim(), *self.scullp_order)?)?;
+            }
+        }
+        Ok(backends)
+    }
+
+    fn check_params(&self) -> Result {
+        if *self.scull_major > SCULL_MAX_MAJOR {
+            pr_err!("scull_major {} is out of range (0..={})\n", *self.scull_major, SCULL_MAX_MAJOR);
+            return Err(Error::EINVAL);
+        }
+        if *self.scull_nr_devs == 0 || *self.scull_nr_devs > SCULL_MAX_NR_DEVS {
+            pr_err!("scull_nr_devs {} is out of range (1..={})\n", *self.scull_nr_devs, SCULL_MAX_NR_DEVS);
+            return Err(Error::EINVAL);
+        }
+        if *self.scull_minor as usize + self.total_devs() > SCULL_MAX_MINOR as usize + 1 {
+            pr_err!("scull_minor {} leaves no room for {} devices\n", *self.scull_minor, self.total_devs());
+            return Err(Error::EINVAL);
+        }
+        let quantum = *self.scull_quantum;
+        if check_block_size(quantum).is_err() {
+            pr_err!("scull_quantum {} must be a multiple of {} up to {}\n", quantum, PAGE_SIZE, SCULL_MAX_BLOCK_SIZE);
+            return Err(Error::EINVAL);
+        }
+        if check_qset(*self.scull_qset).is_err() {
+            pr_err!("scull_qset {} is out of range (1..={})\n", *self.scull_qset, SCULL_MAX_QSET);
+            return Err(Error::EINVAL);
+        }
+        if *self.scullp_order >= usize::BITS || PAGE_SIZE << *self.scullp_order > SCULL_MAX_BLOCK_SIZE {
+            pr_err!("scullp_order {} makes blocks larger than {}\n", *self.scullp_order, SCULL_MAX_BLOCK_SIZE);
+            return Err(Error::EINVAL);
+        }
+        if !self.scull_backends.is_empty() {
+            for name in self.scull_backends.split(',') {
+                if ScullBackend::from_name(name.trim(), *self.scullp_order).is_err() {
+                    pr_err!("scull_backends: unknown backend \"{}\", expected one of {:?}\n", name, &SCULL_BACKEND_NAMES[..3]);
+                    return Err(Error::EINVAL);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Add the cdev of `new` once it sits in `owner`, since a cdev can't move once
+    // added. On failure `owner` goes back to None, so that teardown only deletes
+    // the cdevs that were added.
+    fn add_cdev<T>(owner: &mut Option<T>, new: T, cdev: fn(&mut T) -> &mut CDev, devno: DevT, count: usize) -> Result {
+        if let Err(e) = cdev(owner.insert(new)).add(devno, count as u32) {
+            *owner = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Everything past the chrdev region. Each field is only set once what it
+    // stands for is set up, so that teardown can undo a setup that failed half way.
+    fn setup(&mut self) -> Result {
+        let nr_devs = *self.scull_nr_devs;
+        let total_devs = self.total_devs() as u32;
+
+        let class = Class::create(THIS_MODULE, SCULL_CLASS_NAME)?;
+        class.create_attrs::<ScullClassAttrs>()?;
+
+        let debugfs = debugfs::Dir::create(SCULL_DEBUGFS_NAME)?;
+        register_alloc_stats(&debugfs)?;
+
+        let registry = Arc::try_new(ScullRegistry {
+            devs: Mutex::new(Vec::new()),
+            scullc_devs: Mutex::new(Default::default()),
+            backends: self.backends()?,
+            debugfs,
+            class,
+            major: self.major,
+            minor: self.minor,
+        })?;
+        {
+            let mut devs = registry.devs.lock();
+            devs.try_resize_with(SCULL_MAX_NR_DEVS, || None)?;
+            for i in 0..nr_devs {
+                devs[i] = Some(registry.dev_create(i)?);
+            }
+        }
+        self.registry = Some(registry.clone());
+
+        let devno = kernel::MKDEV(self.major, self.minor);
+        Self::add_cdev(&mut self.scull_cdev, ScullCdev::new(registry.clone()), |sc| &mut sc.cdev, devno, SCULL_MAX_NR_DEVS)?;
+
+        // The other nodes have no sysfs attributes, and are created once and for all
+        let class = &registry.class;
+
+        // Pipe devices take the minors right after the plain ones
+        for i in 0..SCULL_P_NR_DEVS {
+            let devno = kernel::MKDEV(self.major, self.minor + (SCULL_MAX_NR_DEVS + i) as u32);
+            Self::add_cdev(&mut self.pipes[i], ScullPipe::new(), |pipe| &mut pipe.cdev, devno, 1)?;
+            self.nodes.try_push(class.device_create(devno, fmt!("scullpipe{}", i))?)?;
+        }
+
+        let policies = [
+            (AccessPolicy::Single, "scullsingle"),
+            (AccessPolicy::Uid, "sculluid"),
+            (AccessPolicy::WaitUid, "scullwuid"),
+        ];
+        for (i, (policy, name)) in policies.iter().enumerate() {
+            let devno = kernel::MKDEV(self.major, self.minor + (SCULL_MAX_NR_DEVS + SCULL_P_NR_DEVS + i) as u32);
+            Self::add_cdev(&mut self.access_devs[i], ScullAccess::new(*policy)?, ScullAccess::cdev, devno, 1)?;
+            self.nodes.try_push(class.device_create(devno, fmt!("{}", name))?)?;
+        }
+
+        let devno = kernel::MKDEV(self.major, self.minor + total_devs - 2);
+        Self::add_cdev(&mut self.priv_dev, ScullPriv::new(), |sp| &mut sp.cdev, devno, 1)?;
+        self.nodes.try_push(class.device_create(devno, fmt!("scullpriv"))?)?;
+
+        let devno = kernel::MKDEV(self.major, self.minor + total_devs - 1);
+        Self::add_cdev(&mut self.control, ScullControl::new(registry.clone()), |ctl| &mut ctl.cdev, devno, 1)?;
+        self.nodes.try_push(class.device_create(devno, fmt!("{}", SCULL_CONTROL_NAME))?)?;
+
+        // One cache object per block, so the block size is fixed at load time
+        let backend = ScullBackend::Cache(Arc::try_new(KmemCache::create(SCULLC_CACHE_NAME, *self.scull_quantum)?)?);
+        {
+            let mut scullc_devs = registry.scullc_devs.lock();
+            for i in 0..SCULLC_NR_DEVS {
+                scullc_devs[i] = Some(registry.dev_setup(SCULLC_FIRST + i, fmt!("scullc{}", i), backend.clone())?);
+            }
+        }
+        let devno = kernel::MKDEV(self.major, self.minor + SCULLC_FIRST as u32);
+        Self::add_cdev(&mut self.scullc_cdev, ScullCdev::new(registry.clone()), |sc| &mut sc.cdev, devno, SCULLC_NR_DEVS)?;
+
+        // Set up last and together, see teardown
+        procfs::create_seq::<ScullSeqOps>(SCULL_PROC_NAME, &registry.devs)?;
+        match ScullShrinker::register(registry) {
+            Ok(shrinker) => self.shrinker = Some(shrinker),
+            Err(e) => {
+                procfs::remove(SCULL_PROC_NAME);
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Undo setup, all of it on exit or whatever it got to when it failed
+    fn teardown(&mut self) {
+        // Waits for running scans, none can start after this
+        if self.shrinker.take().is_some() {
+            procfs::remove(SCULL_PROC_NAME);
+        }
+
+        // Drop the /dev entries first so that nobody can open a node being torn down
+        self.nodes.clear();
+        // Nothing can add devices once scull-control is gone
+        if let Some(ctl) = self.control.take() {
+            ctl.cdev.del();
+        }
+        for sc in [self.scull_cdev.take(), self.scullc_cdev.take()].into_iter().flatten() {
+            sc.cdev.del();
+        }
+        // No file is left, so these are the last references
+        if let Some(registry) = self.registry.as_deref() {
+            for slot in registry.devs.lock().iter_mut() {
+                slot.take();
+            }
+        }
+        for pipe in self.pipes.iter_mut() {
+            if let Some(pipe) = pipe.take() {
+                pipe.cdev.del();
+            }
+        }
+        for acc in self.access_devs.iter_mut() {
+            if let Some(mut acc) = acc.take() {
+                acc.cdev().del();
+                let _ = acc.trim();
+            }
+        }
+        if let Some(mut sp) = self.priv_dev.take() {
+            sp.cdev.del();
+            sp.release_all();
+        }
+        // The scullc cache goes with the last of these
+        if let Some(registry) = self.registry.as_deref() {
+            for slot in registry.scullc_devs.lock().iter_mut() {
+                slot.take();
+            }
+        }
+        // scull-control and the shrinker are gone, this is the last reference
+        self.registry.take();
+    }
+}
+
 impl KernelModule for ScullModule {
     fn init(self) -> Result {
         pr_info!("Scull module is loaded\n");
-        
-        let mut err = 0;
-        let devno = kernel::chrdev::alloc_chrdev_region(None, self.minor, SCULL_NR_DEVS as u32, b"scull_module")?;
-        self.major = kernel::major(devno);
-        self.minor = kernel::minor(devno);
-        
-        for i in 0..SCULL_NR_DEVS {
-            self.devs[i] = Some(ScullDev::new());
-            let dev = self.devs[i].as_mut().unwrap();
-            let devno = kernel::MKDEV(self.major, self.minor + i as u32);
-            dev.cdev.add(devno, 1)?;
 
-            // Additional initialization steps like creating device entries can go here.
+        self.check_params()?;
+        SCULL_QUANTUM_CUR.store(*self.scull_quantum, Ordering::Relaxed);
+        SCULL_QSET_CUR.store(*self.scull_qset, Ordering::Relaxed);
+        SCULL_DEV_LIMIT.store(*self.scull_dev_limit, Ordering::Relaxed);
+        SCULL_MEM_LIMIT.store(*self.scull_mem_limit, Ordering::Relaxed);
+
+        let total_devs = self.total_devs() as u32;
+
+        // Ask for a dynamic major unless directed otherwise at load time
+        if *self.scull_major != 0 {
+            let devno = kernel::MKDEV(*self.scull_major, *self.scull_minor);
+            kernel::chrdev::register_chrdev_region(devno, total_devs, b"scull_module")?;
+            self.major = *self.scull_major;
+            self.minor = *self.scull_minor;
+        } else {
+            let devno = kernel::chrdev::alloc_chrdev_region(None, *self.scull_minor, total_devs, b"scull_module")?;
+            self.major = kernel::major(devno);
+            self.minor = kernel::minor(devno);
         }
 
+        // Exit won't run if init fails, so undo what was done here
+        if let Err(e) = self.setup() {
+            self.teardown();
+            let _ = kernel::chrdev::unregister_chrdev_region(MKDEV(self.major, self.minor), total_devs);
+            return Err(e);
+        }
         Ok(())
     }
 }
@@ -92,13 +881,8 @@ impl KernelModule for ScullModule {
     fn exit(self) -> Result {
         pr_info!("Scull module unloaded\n");
 
-        for dev in self.devs.iter_mut() {
-            if let Some(dev) = dev.take() {
-                dev.cdev.del();
-                dev.trim();
-            }
-        }
-        kernel::chrdev::unregister_chrdev_region(MKDEV(self.major, self.minor), SCULL_NR_DEVS as u32)?;
+        self.teardown();
+        kernel::chrdev::unregister_chrdev_region(MKDEV(self.major, self.minor), self.total_devs() as u32)?;
         Ok(())
     }
 }
@@ -107,88 +891,213 @@ impl file_operations::FileOpener for ScullModule {
     fn open(ctx: &kernel::file_operations::FileContext) -> Result {
         pr_debug!("open() is invoked\n");
 
-        let dev = &mut ctx.private_data().as_mut::<ScullDev>();
-        
-        if ctx.flags().contains(file_operations::FileOpenFlag::WRITE_ONLY) {
-            dev.mutex.lock_interruptible()?;
-            dev.trim();
-            dev.mutex.unlock();
+        // The file starts out on the shared node, and from here on works on the device
+        let sc = &ctx.private_data().as_mut::<ScullCdev>();
+        let minor = (kernel::minor(ctx.devno()) - sc.registry.minor) as usize;
+        let dev = sc.registry.dev_get(minor)?;
+        ctx.set_private_data(dev);
+
+        // SAFETY: `dev_get` took a reference for this file
+        let ret = unsafe { (*dev).open(ctx) };
+        if ret.is_err() {
+            // SAFETY: the file is done with it
+            unsafe { ScullDev::put(dev) };
         }
-        
-        Ok(())
+        ret
     }
 }
 
 impl file_operations::FileCloser for ScullModule {
     fn release(ctx: &kernel::file_operations::FileContext) -> Result {
         pr_debug!("release() is invoked\n");
-        Ok(())
+
+        let dev = &mut *ctx.private_data().as_mut::<ScullDev>() as *mut ScullDev;
+        // SAFETY: the reference taken on open is dropped last, the device may go with it
+        let ret = unsafe { (*dev).release(ctx) };
+        unsafe { ScullDev::put(dev) };
+        ret
     }
 }
 
-impl file_operations::FileReader for ScullModule {
-    fn read(ctx: &kernel::file_operations::FileContext, buf: &mut [u8], count: usize, offset: usize) -> Result<usize> {
-        pr_debug!("read() is invoked\n");
-
-        let dev = &ctx.private_data().as_mut::<ScullDev>();
-        let tblock = offset / SCULL_BLOCK_SIZE;
-        let toffset = offset % SCULL_BLOCK_SIZE;
+// Both sides work on an iov_iter, so read/readv/preadv2 and io_uring all end
+// up here, and splice and sendfile through the splice ops below
+impl file_operations::FileReadIter for ScullModule {
+    fn read_iter(kiocb: &mut Kiocb, iter: &mut IovIter) -> Result<usize> {
+        pr_debug!("read_iter() is invoked\n");
 
-        dev.mutex.lock_interruptible()?;
+        let dev = &kiocb.file().private_data().as_mut::<ScullDev>();
+        dev.faults.read.check()?;
+        iter.truncate(dev.faults.read.limit(iter.count()));
+        dev.lock_read(kiocb)?;
 
-        if tblock + 1 > dev.block_counter {
+        let offset = kiocb.pos() as usize;
+        if offset >= dev.store.size() {
+            dev.sem.up_read();
             return Ok(0); // End of file
         }
+        let count = iter.count();
+        let quantum = dev.store.quantum();
+
+        let mut pos = offset;
+        let ret = dev.store.read_with(offset, count, |block, toffset, chunk| {
+            let copied = match block {
+                Some(pblock) => {
+                    // Mapped pages change without going through write, nothing to check them against
+                    if dev.vmas.load(Ordering::Relaxed) == 0 {
+                        if let Err(e) = pblock.verify() {
+                            // Past the first block the good part is handed out, the error comes on the next call
+                            pr_warn!("scull: checksum mismatch in block {}\n", pos / quantum);
+                            return Err(e);
+                        }
+                    }
+                    pblock.copy_to_iter(toffset, chunk, iter)
+                }
+                None => iter.zero(chunk), // Sparse gap
+            };
+            pos += copied;
+            Ok(copied) // Short on a bad user buffer
+        });
+        let read_count = match ret {
+            Ok(read_count) => read_count,
+            Err(e) => {
+                dev.sem.up_read();
+                return Err(e);
+            }
+        };
+
+        pr_debug!("RD pos = {}, block = {}, offset = {}, read {} bytes\n", offset, offset / quantum, offset % quantum, read_count);
 
-        let mut plist = &dev.block_list;
-        for _ in 0..tblock + 1 {
-            plist = plist.next();
+        dev.sem.up_read();
+        if read_count == 0 && count > 0 {
+            return Err(Error::EFAULT);
         }
+        kiocb.set_pos((offset + read_count) as u64);
+        Ok(read_count)
+    }
+}
 
-        let pblock = unsafe { plist.as_mut::<ScullBlock>() };
-        if toffset >= pblock.offset {
-            return Ok(0); // End of file
+impl file_operations::FileWriteIter for ScullModule {
+    fn write_iter(kiocb: &mut Kiocb, iter: &mut IovIter) -> Result<usize> {
+        pr_debug!("write_iter() is invoked\n");
+
+        let dev = &mut kiocb.file().private_data().as_mut::<ScullDev>();
+        dev.faults.write.check()?;
+        iter.truncate(dev.faults.write.limit(iter.count()));
+        dev.lock_write(kiocb)?;
+
+        // O_APPEND and RWF_APPEND writes always go to the current end, whatever the file position
+        let offset = if kiocb.flags().contains(IocbFlag::APPEND) { dev.store.size() } else { kiocb.pos() as usize };
+        let count = iter.count();
+        let quantum = dev.store.quantum();
+
+        // Blocks that can't be allocated end the write, what made it so far is reported
+        // and the error comes back on the next call. They are allocated first, under
+        // `index`, since the copy may fault on a mapping of this device, see mmap.rs.
+        let alloc = dev.allocator();
+        let index = dev.index.lock();
+        let ret = dev.store.reserve(offset, count, alloc);
+        drop(index);
+        let reserved = match ret {
+            Ok(reserved) => reserved,
+            Err(e) => {
+                dev.sem.up_write();
+                return Err(e);
+            }
+        };
+        let write_count = dev.store.fill_with(offset, reserved, |pblock, toffset, chunk| {
+            let copied = pblock.copy_from_iter(toffset, chunk, iter);
+            if copied > 0 {
+                pblock.offset = pblock.offset.max(toffset + copied);
+            }
+            copied // Short on a bad user buffer
+        });
+        if write_count > 0 {
+            let _index = dev.index.lock();
+            dev.store.grow(offset + write_count);
         }
+        dev.changes += 1; // Even a failed write may have allocated blocks
 
-        let read_count = count.min(pblock.offset - toffset);
-        buf.copy_from_slice(&pblock.data[toffset..toffset + read_count]);
+        pr_debug!("WR pos = {}, block = {}, offset = {}, write {} bytes\n", offset, offset / quantum, offset % quantum, write_count);
 
-        pr_debug!("RD pos = {}, block = {}, offset = {}, read {} bytes\n", offset, tblock, toffset, read_count);
-        
-        dev.mutex.unlock();
-        Ok(read_count)
+        dev.sem.up_write();
+        if write_count == 0 && count > 0 {
+            return Err(Error::EFAULT);
+        }
+        kiocb.set_pos((offset + write_count) as u64);
+
+        // Let pollers know there is new data to read, and signal asynchronous readers
+        dev.waitq.wake_up_interruptible();
+        dev.async_queue.kill(SIGIO, POLL_IN);
+        Ok(write_count)
     }
 }
 
-impl file_operations::FileWriter for ScullModule {
-    fn write(ctx: &kernel::file_operations::FileContext, buf: &[u8], count: usize, offset: usize) -> Result<usize> {
-        pr_debug!("write() is invoked\n");
+// Pipe buffers are filled and drained through read_iter/write_iter
+impl file_operations::FileSpliceRead for ScullModule {
+    fn splice_read(ctx: &kernel::file_operations::FileContext, pos: &mut u64, pipe: &mut PipeInodeInfo, len: usize, flags: u32) -> Result<usize> {
+        pr_debug!("splice_read() is invoked\n");
+        file_operations::copy_splice_read(ctx, pos, pipe, len, flags)
+    }
+}
 
-        let dev = &ctx.private_data().as_mut::<ScullDev>();
-        let tblock = offset / SCULL_BLOCK_SIZE;
-        let toffset = offset % SCULL_BLOCK_SIZE;
+impl file_operations::FileSpliceWrite for ScullModule {
+    fn splice_write(pipe: &mut PipeInodeInfo, ctx: &kernel::file_operations::FileContext, pos: &mut u64, len: usize, flags: u32) -> Result<usize> {
+        pr_debug!("splice_write() is invoked\n");
+        file_operations::iter_file_splice_write(pipe, ctx, pos, len, flags)
+    }
+}
 
-        dev.mutex.lock_interruptible()?;
+impl file_operations::FileSeeker for ScullModule {
+    fn seek(ctx: &kernel::file_operations::FileContext, pos: SeekFrom) -> Result<u64> {
+        pr_debug!("llseek() is invoked\n");
+
+        let dev = &ctx.private_data().as_mut::<ScullDev>();
 
-        let mut pblock: Option<&mut ScullBlock> = None;
+        dev.sem.down_read_interruptible()?;
+        let newpos = match pos {
+            SeekFrom::Start(off) => Some(off as i64),
+            SeekFrom::Current(off) => (ctx.pos() as i64).checked_add(off),
+            // Seeking past the end is allowed, a later write leaves a sparse gap
+            SeekFrom::End(off) => i64::try_from(dev.store.size()).ok().and_then(|size| size.checked_add(off)),
+        };
+        dev.sem.up_read();
 
-        while tblock + 1 > dev.block_counter {
-            let block = Slab::<ScullBlock>::new().alloc();
-            pblock = Some(block);
-            dev.block_list.add_tail(block);
-            dev.block_counter += 1;
+        // Offsets that don't fit in an loff_t can't be represented
+        let newpos = newpos.ok_or(Error::EOVERFLOW)?;
+        if newpos < 0 {
+            return Err(Error::EINVAL);
         }
 
-        pblock = Some(dev.block_list.last().unwrap().as_mut::<ScullBlock>());
-        let pblock = pblock.unwrap();
+        ctx.set_pos(newpos as u64);
+        Ok(newpos as u64)
+    }
+}
 
-        let write_count = (SCULL_BLOCK_SIZE - toffset).min(count);
-        pblock.data[toffset..toffset + write_count].copy_from_slice(&buf[0..write_count]);
-        pblock.offset += write_count;
+impl file_operations::FilePoller for ScullModule {
+    fn poll(ctx: &kernel::file_operations::FileContext, table: &PollTable) -> Result<PollFlags> {
+        pr_debug!("poll() is invoked\n");
 
-        pr_debug!("WR pos = {}, block = {}, offset = {}, write {} bytes\n", offset, tblock, toffset, write_count);
+        let dev = &ctx.private_data().as_mut::<ScullDev>();
 
-        dev.mutex.unlock();
-        Ok(write_count)
+        table.register_wait(&dev.waitq);
+
+        dev.sem.down_read();
+        // Memory backed, so there is always room to write
+        let mut mask = PollFlags::EPOLLOUT | PollFlags::EPOLLWRNORM;
+        if (ctx.pos() as usize) < dev.store.size() {
+            mask |= PollFlags::EPOLLIN | PollFlags::EPOLLRDNORM;
+        }
+        dev.sem.up_read();
+
+        Ok(mask)
+    }
+}
+
+impl file_operations::FileFasync for ScullModule {
+    fn fasync(ctx: &kernel::file_operations::FileContext, fd: i32, on: bool) -> Result {
+        pr_debug!("fasync() is invoked, on = {}\n", on);
+
+        let dev = &mut ctx.private_data().as_mut::<ScullDev>();
+        dev.async_queue.helper(fd, ctx, on)
     }
 }
@@ -0,0 +1,80 @@
+use kernel::prelude::*;
+use kernel::file_operations;
+use kernel::mm::{VmArea, VmFault, VmFaultResult, VmFlags, VmOperations};
+use kernel::pages::PAGE_SHIFT;
+use core::sync::atomic::Ordering;
+
+use crate::{ScullDev, ScullModule};
+
+// The mapping is never populated up front, pages are looked up in the
+// block list on fault. Since file reads and writes go through the same
+// pages both views of the device stay coherent.
+pub(crate) struct ScullVmOps;
+
+impl VmOperations for ScullVmOps {
+    // Called with mmap_lock held, and read_iter/write_iter fault on user memory
+    // with the semaphore held, so neither of these can take it. A mapping can
+    // outlive its file, so it holds a reference of its own.
+    fn open(vma: &VmArea) {
+        let dev = &vma.private_data().as_mut::<ScullDev>();
+        dev.get();
+        dev.vmas.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn close(vma: &VmArea) {
+        let dev = &mut *vma.private_data().as_mut::<ScullDev>() as *mut ScullDev;
+        // SAFETY: the reference taken in `open` is dropped last, the device may go with it
+        unsafe {
+            (*dev).vmas.fetch_sub(1, Ordering::Relaxed);
+            ScullDev::put(dev);
+        }
+    }
+
+    // Not under the semaphore either, a write from this very mapping holds it for
+    // the whole copy. Blocks and the size only change under `index`, which is
+    // never held while touching user memory, and a mapped device isn't trimmed.
+    fn fault(vmf: &mut VmFault) -> VmFaultResult {
+        let dev = &vmf.vma().private_data().as_mut::<ScullDev>();
+        let offset = (vmf.pgoff() as usize) << PAGE_SHIFT;
+
+        let index = dev.index.lock();
+        let quantum = dev.store.quantum();
+        if offset >= dev.store.size() {
+            return VmFaultResult::SIGBUS; // Out of range
+        }
+
+        let pblock = match dev.store.block_at(offset) {
+            Some(pblock) => pblock,
+            None => return VmFaultResult::SIGBUS, // Hole or end-of-file
+        };
+
+        // Got it, now take a reference for the mapping
+        let page = match pblock.mem.page(offset % quantum) {
+            Some(page) => page,
+            None => return VmFaultResult::SIGBUS,
+        };
+        // Writes through the mapping bypass the checksums, the next unmapped scrub takes a new one
+        pblock.invalidate();
+        page.get();
+        vmf.set_page(page);
+        drop(index);
+
+        pr_debug!("fault: pgoff = {}, block = {}\n", vmf.pgoff(), offset / quantum);
+        VmFaultResult::NONE
+    }
+}
+
+impl file_operations::FileMmap for ScullModule {
+    fn mmap(ctx: &kernel::file_operations::FileContext, vma: &mut VmArea) -> Result {
+        pr_debug!("mmap() is invoked\n");
+
+        if !ctx.private_data().as_mut::<ScullDev>().backend.mappable() {
+            return Err(Error::ENODEV);
+        }
+        vma.set_ops::<ScullVmOps>();
+        vma.add_flags(VmFlags::DONTEXPAND | VmFlags::DONTDUMP);
+        vma.set_private_data(ctx.private_data());
+        ScullVmOps::open(vma);
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use kernel::prelude::*;
+use kernel::file_operations::{self, FileOpenFlag, PollFlags, PollTable};
+use kernel::cdev::CDev;
+use kernel::mutex::Mutex;
+use kernel::waitqueue::WaitQueue;
+
+pub(crate) const SCULL_P_NR_DEVS: usize = 4; // Number of pipe devices
+const SCULL_P_BUFFER: usize = 4000; // Circular buffer size
+
+#[derive(Debug)]
+pub(crate) struct ScullPipe {
+    mutex: Mutex<()>,
+    inq: WaitQueue,  // Readers wait here for data
+    outq: WaitQueue, // Writers wait here for space
+    buffer: Vec<u8>,
+    rp: usize, // Where to read
+    wp: usize, // Where to write
+    nreaders: usize,
+    nwriters: usize,
+    pub(crate) cdev: CDev,
+}
+
+impl ScullPipe {
+    pub(crate) fn new() -> Self {
+        ScullPipe {
+            mutex: Mutex::new(()),
+            inq: WaitQueue::new(),
+            outq: WaitQueue::new(),
+            buffer: Vec::new(),
+            rp: 0,
+            wp: 0,
+            nreaders: 0,
+            nwriters: 0,
+            cdev: CDev::with_ops::<ScullPipeOps>(),
+        }
+    }
+
+    // One byte is always left unused so that rp == wp means empty
+    fn spacefree(&self) -> usize {
+        if self.rp == self.wp {
+            return self.buffer.len() - 1;
+        }
+        ((self.rp + self.buffer.len() - self.wp) % self.buffer.len()) - 1
+    }
+}
+
+fn alloc_buffer() -> Result<Vec<u8>> {
+    let mut buffer = Vec::try_with_capacity(SCULL_P_BUFFER)?;
+    buffer.try_resize(SCULL_P_BUFFER, 0u8)?;
+    Ok(buffer)
+}
+
+// Like LDD3, a signal while waiting for the lock tells the fs layer to restart the call
+fn lock(mutex: &Mutex<()>) -> Result {
+    mutex.lock_interruptible().map_err(|_| Error::ERESTARTSYS)
+}
+
+pub(crate) struct ScullPipeOps;
+
+impl file_operations::FileOpener for ScullPipeOps {
+    fn open(ctx: &kernel::file_operations::FileContext) -> Result {
+        pr_debug!("scullpipe open() is invoked\n");
+
+        let dev = &mut ctx.private_data().as_mut::<ScullPipe>();
+
+        lock(&dev.mutex)?;
+        if dev.buffer.is_empty() {
+            match alloc_buffer() {
+                Ok(buffer) => dev.buffer = buffer,
+                Err(e) => {
+                    dev.mutex.unlock();
+                    return Err(e);
+                }
+            }
+            dev.rp = 0;
+            dev.wp = 0;
+        }
+
+        if ctx.flags().contains(FileOpenFlag::READ) {
+            dev.nreaders += 1;
+        }
+        if ctx.flags().contains(FileOpenFlag::WRITE) {
+            dev.nwriters += 1;
+        }
+        dev.mutex.unlock();
+
+        Ok(())
+    }
+}
+
+impl file_operations::FileCloser for ScullPipeOps {
+    fn release(ctx: &kernel::file_operations::FileContext) -> Result {
+        pr_debug!("scullpipe release() is invoked\n");
+
+        let dev = &mut ctx.private_data().as_mut::<ScullPipe>();
+
+        dev.mutex.lock();
+        if ctx.flags().contains(FileOpenFlag::READ) {
+            dev.nreaders -= 1;
+        }
+        if ctx.flags().contains(FileOpenFlag::WRITE) {
+            dev.nwriters -= 1;
+        }
+        if dev.nreaders + dev.nwriters == 0 {
+            // Nobody holds the pipe open any more, drop the buffer
+            dev.buffer = Vec::new();
+        }
+        dev.mutex.unlock();
+
+        Ok(())
+    }
+}
+
+impl file_operations::FileReader for ScullPipeOps {
+    fn read(ctx: &kernel::file_operations::FileContext, buf: &mut [u8], count: usize, _offset: usize) -> Result<usize> {
+        pr_debug!("scullpipe read() is invoked\n");
+
+        let dev = &mut ctx.private_data().as_mut::<ScullPipe>();
+
+        lock(&dev.mutex)?;
+
+        while dev.rp == dev.wp {
+            // Nothing to read, release the lock before going to sleep
+            dev.mutex.unlock();
+            if ctx.flags().contains(FileOpenFlag::NONBLOCK) {
+                return Err(Error::EAGAIN);
+            }
+            pr_debug!("\"{}\" reading: going to sleep\n", kernel::current::comm());
+            if dev.inq.wait_event_interruptible(|| dev.rp != dev.wp).is_err() {
+                return Err(Error::ERESTARTSYS); // Signal: tell the fs layer to handle it
+            }
+            lock(&dev.mutex)?;
+        }
+
+        // Data is there, read up to the write pointer or the end of the buffer
+        let read_count = if dev.wp > dev.rp {
+            count.min(dev.wp - dev.rp)
+        } else {
+            count.min(dev.buffer.len() - dev.rp)
+        };
+        buf[..read_count].copy_from_slice(&dev.buffer[dev.rp..dev.rp + read_count]);
+        dev.rp += read_count;
+        if dev.rp == dev.buffer.len() {
+            dev.rp = 0; // Wrapped
+        }
+        dev.mutex.unlock();
+
+        // Finally, awake any writers
+        dev.outq.wake_up_interruptible();
+        pr_debug!("\"{}\" did read {} bytes\n", kernel::current::comm(), read_count);
+        Ok(read_count)
+    }
+}
+
+impl file_operations::FileWriter for ScullPipeOps {
+    fn write(ctx: &kernel::file_operations::FileContext, buf: &[u8], count: usize, _offset: usize) -> Result<usize> {
+        pr_debug!("scullpipe write() is invoked\n");
+
+        let dev = &mut ctx.private_data().as_mut::<ScullPipe>();
+
+        lock(&dev.mutex)?;
+
+        while dev.spacefree() == 0 {
+            // Full, release the lock before going to sleep
+            dev.mutex.unlock();
+            if ctx.flags().contains(FileOpenFlag::NONBLOCK) {
+                return Err(Error::EAGAIN);
+            }
+            pr_debug!("\"{}\" writing: going to sleep\n", kernel::current::comm());
+            if dev.outq.wait_event_interruptible(|| dev.spacefree() != 0).is_err() {
+                return Err(Error::ERESTARTSYS);
+            }
+            lock(&dev.mutex)?;
+        }
+
+        // Space is there, write up to the read pointer or the end of the buffer
+        let mut write_count = count.min(dev.spacefree());
+        if dev.wp >= dev.rp {
+            write_count = write_count.min(dev.buffer.len() - dev.wp);
+        } else {
+            write_count = write_count.min(dev.rp - dev.wp - 1);
+        }
+        let wp = dev.wp;
+        dev.buffer[wp..wp + write_count].copy_from_slice(&buf[..write_count]);
+        dev.wp += write_count;
+        if dev.wp == dev.buffer.len() {
+            dev.wp = 0; // Wrapped
+        }
+        dev.mutex.unlock();
+
+        // Finally, awake any reader
+        dev.inq.wake_up_interruptible();
+        pr_debug!("\"{}\" did write {} bytes\n", kernel::current::comm(), write_count);
+        Ok(write_count)
+    }
+}
+
+impl file_operations::FilePoller for ScullPipeOps {
+    fn poll(ctx: &kernel::file_operations::FileContext, table: &PollTable) -> Result<PollFlags> {
+        pr_debug!("scullpipe poll() is invoked\n");
+
+        let dev = &ctx.private_data().as_mut::<ScullPipe>();
+
+        table.register_wait(&dev.inq);
+        table.register_wait(&dev.outq);
+
+        dev.mutex.lock();
+        let mut mask = PollFlags::empty();
+        if dev.rp != dev.wp {
+            mask |= PollFlags::EPOLLIN | PollFlags::EPOLLRDNORM; // Readable
+        }
+        if dev.spacefree() != 0 {
+            mask |= PollFlags::EPOLLOUT | PollFlags::EPOLLWRNORM; // Writable
+        }
+        dev.mutex.unlock();
+
+        Ok(mask)
+    }
+}
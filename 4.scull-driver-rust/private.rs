@@ -0,0 +1,141 @@
+use kernel::prelude::*;
+use kernel::file_operations::{self, Kiocb, PollFlags, PollTable, SeekFrom};
+use kernel::cdev::CDev;
+use kernel::iov_iter::IovIter;
+use kernel::pipe::PipeInodeInfo;
+use kernel::mm::VmArea;
+use kernel::mutex::Mutex;
+use kernel::tty;
+use kernel::types::DevT;
+
+use crate::{ScullDev, ScullModule};
+
+// One lazily created device per controlling tty
+#[derive(Debug)]
+struct ScullListItem {
+    key: DevT,
+    dev: Box<ScullDev>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ScullPriv {
+    list: Mutex<Vec<ScullListItem>>,
+    pub(crate) cdev: CDev,
+}
+
+impl ScullPriv {
+    pub(crate) fn new() -> Self {
+        ScullPriv {
+            list: Mutex::new(Vec::new()),
+            cdev: CDev::with_ops::<ScullPrivOps>(),
+        }
+    }
+
+    // Look for a device for `key` or create one if missing
+    fn lookfor_device(&self, key: DevT) -> Result<*mut ScullDev> {
+        let mut list = self.list.lock_interruptible()?;
+        if let Some(item) = list.iter_mut().find(|item| item.key == key) {
+            return Ok(&mut *item.dev as *mut ScullDev);
+        }
+
+        pr_debug!("scullpriv: new device for tty {:#x}\n", key);
+        list.try_push(ScullListItem {
+            key,
+            dev: Box::try_new(ScullDev::new()?)?,
+        })?;
+        Ok(&mut *list.last_mut().unwrap().dev as *mut ScullDev)
+    }
+
+    pub(crate) fn release_all(&mut self) {
+        let mut list = self.list.lock();
+        for item in list.iter_mut() {
+            let _ = item.dev.trim();
+        }
+        list.clear();
+    }
+}
+
+pub(crate) struct ScullPrivOps;
+
+impl file_operations::FileOpener for ScullPrivOps {
+    fn open(ctx: &kernel::file_operations::FileContext) -> Result {
+        pr_debug!("scullpriv open() is invoked\n");
+
+        let sp = &ctx.private_data().as_mut::<ScullPriv>();
+
+        // Processes without a controlling tty have nothing to be keyed on
+        let key = match tty::current_tty() {
+            Some(tty) => tty.devno(),
+            None => {
+                pr_debug!("scullpriv: process \"{}\" has no ctl tty\n", kernel::current::comm());
+                return Err(Error::EINVAL);
+            }
+        };
+
+        let dev = sp.lookfor_device(key)?;
+        // From here on the file works on the per-tty device like plain scull
+        ctx.set_private_data(dev);
+        ctx.private_data().as_mut::<ScullDev>().open(ctx)
+    }
+}
+
+impl file_operations::FileCloser for ScullPrivOps {
+    fn release(ctx: &kernel::file_operations::FileContext) -> Result {
+        // Nothing to put here, the per-tty devices live until the module goes away
+        ctx.private_data().as_mut::<ScullDev>().release(ctx)
+    }
+}
+
+impl file_operations::FileReadIter for ScullPrivOps {
+    fn read_iter(kiocb: &mut Kiocb, iter: &mut IovIter) -> Result<usize> {
+        <ScullModule as file_operations::FileReadIter>::read_iter(kiocb, iter)
+    }
+}
+
+impl file_operations::FileWriteIter for ScullPrivOps {
+    fn write_iter(kiocb: &mut Kiocb, iter: &mut IovIter) -> Result<usize> {
+        <ScullModule as file_operations::FileWriteIter>::write_iter(kiocb, iter)
+    }
+}
+
+impl file_operations::FileSpliceRead for ScullPrivOps {
+    fn splice_read(ctx: &kernel::file_operations::FileContext, pos: &mut u64, pipe: &mut PipeInodeInfo, len: usize, flags: u32) -> Result<usize> {
+        <ScullModule as file_operations::FileSpliceRead>::splice_read(ctx, pos, pipe, len, flags)
+    }
+}
+
+impl file_operations::FileSpliceWrite for ScullPrivOps {
+    fn splice_write(pipe: &mut PipeInodeInfo, ctx: &kernel::file_operations::FileContext, pos: &mut u64, len: usize, flags: u32) -> Result<usize> {
+        <ScullModule as file_operations::FileSpliceWrite>::splice_write(pipe, ctx, pos, len, flags)
+    }
+}
+
+impl file_operations::FileSeeker for ScullPrivOps {
+    fn seek(ctx: &kernel::file_operations::FileContext, pos: SeekFrom) -> Result<u64> {
+        <ScullModule as file_operations::FileSeeker>::seek(ctx, pos)
+    }
+}
+
+impl file_operations::FilePoller for ScullPrivOps {
+    fn poll(ctx: &kernel::file_operations::FileContext, table: &PollTable) -> Result<PollFlags> {
+        <ScullModule as file_operations::FilePoller>::poll(ctx, table)
+    }
+}
+
+impl file_operations::FileFasync for ScullPrivOps {
+    fn fasync(ctx: &kernel::file_operations::FileContext, fd: i32, on: bool) -> Result {
+        <ScullModule as file_operations::FileFasync>::fasync(ctx, fd, on)
+    }
+}
+
+impl file_operations::FileMmap for ScullPrivOps {
+    fn mmap(ctx: &kernel::file_operations::FileContext, vma: &mut VmArea) -> Result {
+        <ScullModule as file_operations::FileMmap>::mmap(ctx, vma)
+    }
+}
+
+impl file_operations::FileIoctl for ScullPrivOps {
+    fn ioctl(ctx: &kernel::file_operations::FileContext, cmd: u32, arg: usize) -> Result<i32> {
+        <ScullModule as file_operations::FileIoctl>::ioctl(ctx, cmd, arg)
+    }
+}
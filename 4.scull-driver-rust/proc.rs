@@ -0,0 +1,89 @@
+use kernel::prelude::*;
+use kernel::mutex::Mutex;
+use kernel::seq_file::{SeqFile, SeqOperations};
+use kernel::seq_printf;
+
+use crate::ScullSlot;
+
+pub(crate) const SCULL_PROC_NAME: &str = "scullmem";
+
+// Records are numbered across all devices: one header per device followed by
+// one record per qset, so a long device is split over several reads instead
+// of having to fit in a single page. Devices added or removed through
+// scull-control between two reads just shift the records that follow.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScullSeqPos {
+    dev: usize,
+    qset: Option<usize>,
+}
+
+fn locate(devs: &Mutex<Vec<Option<ScullSlot>>>, pos: u64) -> Option<ScullSeqPos> {
+    let devs = devs.lock();
+    let mut pos = pos as usize;
+    for (i, slot) in devs.iter().enumerate() {
+        let dev = match slot {
+            Some(slot) => &slot.dev,
+            None => continue,
+        };
+
+        dev.sem.down_read();
+        let records = 1 + dev.store.qsets().count();
+        dev.sem.up_read();
+
+        if pos < records {
+            let qset = if pos == 0 { None } else { Some(pos - 1) };
+            return Some(ScullSeqPos { dev: i, qset });
+        }
+        pos -= records;
+    }
+    None // End of the sequence
+}
+
+pub(crate) struct ScullSeqOps;
+
+impl SeqOperations for ScullSeqOps {
+    type Data = Mutex<Vec<Option<ScullSlot>>>;
+    type Item = ScullSeqPos;
+
+    fn start(m: &SeqFile, pos: &mut u64) -> Option<ScullSeqPos> {
+        locate(m.private::<Self::Data>(), *pos)
+    }
+
+    fn next(m: &SeqFile, _item: ScullSeqPos, pos: &mut u64) -> Option<ScullSeqPos> {
+        *pos += 1;
+        locate(m.private::<Self::Data>(), *pos)
+    }
+
+    fn stop(_m: &SeqFile, _item: Option<ScullSeqPos>) {
+        // Nothing to do, every record takes the device lock on its own
+    }
+
+    fn show(m: &SeqFile, item: ScullSeqPos) -> Result {
+        let devs = m.private::<Self::Data>().lock_interruptible()?;
+        let dev = match &devs[item.dev] {
+            Some(slot) => &slot.dev,
+            None => return Ok(()), // Removed since `start`
+        };
+
+        dev.sem.down_read_interruptible()?;
+        match item.qset {
+            None => {
+                let used: usize = dev.store.blocks().map(|(_, pblock)| pblock.offset).sum();
+                seq_printf!(m, "\nDevice {}: qset {}, quantum {}, size {}, blocks {}, used {}\n",
+                    item.dev, dev.store.qset(), dev.store.quantum(), dev.store.size(), dev.store.block_count(), used);
+            }
+            Some(n) => {
+                // The device may have shrunk since `start`, just skip what's gone
+                if let Some(qs) = dev.store.qsets().nth(n) {
+                    seq_printf!(m, "  qset {}: {} blocks\n", n, qs.blocks().count());
+                    for (i, pblock) in qs.blocks() {
+                        seq_printf!(m, "    block {:6}: {:6}/{} bytes\n",
+                            n * dev.store.qset() + i, pblock.offset, dev.store.quantum());
+                    }
+                }
+            }
+        }
+        dev.sem.up_read();
+        Ok(())
+    }
+}
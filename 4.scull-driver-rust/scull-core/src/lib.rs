@@ -0,0 +1,522 @@
+//! Storage core of the scull driver, without any kernel dependency.
+//!
+//! Data is laid out like in LDD3: blocks of `quantum` bytes, held by qsets of
+//! `qset` slots chained together. Blocks are only allocated when written, so
+//! a device can be sparse, and holes read back as zeros. The kernel module
+//! supplies the block type and how blocks are allocated, this crate does the
+//! bookkeeping.
+#![no_std]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::convert::Infallible;
+use core::mem::size_of;
+
+/// A qset couldn't be allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+/// Block size and qset length of a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Geometry {
+    quantum: usize,
+    qset: usize,
+}
+
+/// Where a byte lives: qset number, slot in the qset and offset in the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Locus {
+    pub item: usize,
+    pub slot: usize,
+    pub offset: usize,
+}
+
+impl Geometry {
+    /// None if either is zero, or if a qset would cover more than `usize::MAX` bytes.
+    pub const fn new(quantum: usize, qset: usize) -> Option<Geometry> {
+        if quantum == 0 || qset == 0 || quantum.checked_mul(qset).is_none() {
+            return None;
+        }
+        Some(Geometry { quantum, qset })
+    }
+
+    pub const fn quantum(&self) -> usize {
+        self.quantum
+    }
+
+    pub const fn qset(&self) -> usize {
+        self.qset
+    }
+
+    /// Bytes covered by one qset.
+    pub const fn itemsize(&self) -> usize {
+        self.quantum * self.qset
+    }
+
+    pub const fn locate(&self, pos: usize) -> Locus {
+        Locus {
+            item: pos / self.itemsize(),
+            slot: (pos % self.itemsize()) / self.quantum,
+            offset: pos % self.quantum,
+        }
+    }
+
+    pub const fn block_index(&self, pos: usize) -> usize {
+        pos / self.quantum
+    }
+
+    /// Bytes from `pos` to the end of its block, at most `remaining`.
+    pub fn chunk(&self, pos: usize, remaining: usize) -> usize {
+        (self.quantum - pos % self.quantum).min(remaining)
+    }
+
+    /// Blocks needed to hold `size` bytes.
+    pub const fn blocks_for(&self, size: usize) -> usize {
+        size.div_ceil(self.quantum)
+    }
+}
+
+/// A block of `quantum` bytes, zeroed when allocated.
+pub trait Block {
+    fn read_at(&self, offset: usize, buf: &mut [u8]);
+    fn write_at(&mut self, offset: usize, buf: &[u8]);
+    /// Zero everything from `offset` to the end of the block.
+    fn zero_from(&mut self, offset: usize);
+}
+
+/// Where a store gets its memory from. A closure returning new blocks is an
+/// allocator that lets every qset through.
+pub trait Alloc<B> {
+    type Error: From<AllocError>;
+
+    /// A new block, zeroed.
+    fn block(&mut self) -> Result<B, Self::Error>;
+
+    /// A qset of `bytes` was just allocated. An error frees it again and ends
+    /// the allocation.
+    fn qset(&mut self, _bytes: usize) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<B, E: From<AllocError>, F: FnMut() -> Result<B, E>> Alloc<B> for F {
+    type Error = E;
+
+    fn block(&mut self) -> Result<B, E> {
+        self()
+    }
+}
+
+/// `qset` slots, None until the block is first written.
+#[derive(Debug)]
+pub struct Qset<B> {
+    data: Vec<Option<B>>,
+    next: Option<Box<Qset<B>>>,
+}
+
+impl<B> Qset<B> {
+    // The kernel's alloc is built without the infallible allocations, host
+    // builds use them since the fallible ones aren't stable
+    #[cfg(not(feature = "global-oom-handling"))]
+    fn new(qset: usize) -> Result<Box<Self>, AllocError> {
+        let mut data = Vec::new();
+        data.try_resize_with(qset, || None).map_err(|_| AllocError)?;
+        Box::try_new(Qset { data, next: None }).map_err(|_| AllocError)
+    }
+
+    #[cfg(feature = "global-oom-handling")]
+    fn new(qset: usize) -> Result<Box<Self>, AllocError> {
+        let mut data = Vec::new();
+        data.try_reserve_exact(qset).map_err(|_| AllocError)?;
+        data.resize_with(qset, || None);
+        Ok(Box::new(Qset { data, next: None }))
+    }
+
+    /// Allocated blocks with their slot number.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, &B)> {
+        self.data.iter().enumerate().filter_map(|(i, block)| block.as_ref().map(|block| (i, block)))
+    }
+}
+
+// Dropping a long chain in one go would recurse once per qset
+fn unlink<B>(mut next: Option<Box<Qset<B>>>) {
+    while let Some(mut qs) = next {
+        next = qs.next.take();
+    }
+}
+
+struct QsetsMut<'a, B>(Option<&'a mut Qset<B>>);
+
+impl<'a, B> Iterator for QsetsMut<'a, B> {
+    type Item = &'a mut [Option<B>];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Qset { data, next } = self.0.take()?;
+        self.0 = next.as_deref_mut();
+        Some(data)
+    }
+}
+
+#[derive(Debug)]
+pub struct Store<B> {
+    geometry: Geometry,
+    data: Option<Box<Qset<B>>>,
+    size: usize,   // Logical size, may include sparse regions
+    blocks: usize, // Blocks actually allocated, holes don't count
+    qsets: usize,  // Length of the chain
+}
+
+impl<B> Store<B> {
+    pub const fn new(geometry: Geometry) -> Self {
+        Store { geometry, data: None, size: 0, blocks: 0, qsets: 0 }
+    }
+
+    pub fn geometry(&self) -> Geometry {
+        self.geometry
+    }
+
+    pub fn quantum(&self) -> usize {
+        self.geometry.quantum
+    }
+
+    pub fn qset(&self) -> usize {
+        self.geometry.qset
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Number of allocated blocks.
+    pub fn block_count(&self) -> usize {
+        self.blocks
+    }
+
+    /// Bytes held by allocated blocks.
+    pub fn mem_used(&self) -> usize {
+        self.blocks * self.geometry.quantum
+    }
+
+    /// Number of qsets in the chain.
+    pub fn qset_count(&self) -> usize {
+        self.qsets
+    }
+
+    /// Bytes held by the qsets themselves, on top of their blocks.
+    pub fn qset_mem(&self) -> usize {
+        self.qsets * Self::qset_bytes(self.geometry.qset)
+    }
+
+    fn qset_bytes(qset: usize) -> usize {
+        size_of::<Qset<B>>() + qset * size_of::<Option<B>>()
+    }
+
+    pub fn qsets(&self) -> impl Iterator<Item = &Qset<B>> {
+        core::iter::successors(self.data.as_deref(), |qs| qs.next.as_deref())
+    }
+
+    // Every slot in offset order, with its block index
+    fn slots_mut(&mut self) -> impl Iterator<Item = (usize, &mut Option<B>)> {
+        let qset = self.geometry.qset;
+        QsetsMut(self.data.as_deref_mut())
+            .enumerate()
+            .flat_map(move |(n, data)| data.iter_mut().enumerate().map(move |(i, slot)| (n * qset + i, slot)))
+    }
+
+    /// Allocated blocks in offset order, with their block index.
+    pub fn blocks(&self) -> impl Iterator<Item = (usize, &B)> {
+        let qset = self.geometry.qset;
+        self.qsets()
+            .enumerate()
+            .flat_map(move |(n, qs)| qs.blocks().map(move |(i, block)| (n * qset + i, block)))
+    }
+
+    /// The first allocated block at or after block `index`, with its index.
+    pub fn next_block(&self, index: usize) -> Option<(usize, &B)> {
+        let qset = self.geometry.qset;
+        self.qsets()
+            .enumerate()
+            .skip(index / qset)
+            .flat_map(move |(n, qs)| qs.blocks().map(move |(i, block)| (n * qset + i, block)))
+            .find(|&(i, _)| i >= index)
+    }
+
+    pub fn blocks_mut(&mut self) -> impl Iterator<Item = (usize, &mut B)> {
+        self.slots_mut().filter_map(|(index, slot)| slot.as_mut().map(|block| (index, block)))
+    }
+
+    /// The block holding byte `pos`, None if it was never written.
+    pub fn block_at(&self, pos: usize) -> Option<&B> {
+        let locus = self.geometry.locate(pos);
+        self.qsets().nth(locus.item)?.data[locus.slot].as_ref()
+    }
+
+    pub fn block_at_mut(&mut self, pos: usize) -> Option<&mut B> {
+        let locus = self.geometry.locate(pos);
+        QsetsMut(self.data.as_deref_mut()).nth(locus.item)?[locus.slot].as_mut()
+    }
+
+    // A new qset, refused by `alloc` if it doesn't fit
+    fn new_qset<A: Alloc<B>>(qset: usize, alloc: &mut A) -> Result<Box<Qset<B>>, A::Error> {
+        let qs = Qset::new(qset)?;
+        alloc.qset(Self::qset_bytes(qset))?;
+        Ok(qs)
+    }
+
+    // Walk to qset `n` of `data`, allocating the missing ones on the way and
+    // counting them in `qsets`
+    fn follow<'a, A: Alloc<B>>(
+        data: &'a mut Option<Box<Qset<B>>>,
+        qsets: &mut usize,
+        qset: usize,
+        n: usize,
+        alloc: &mut A,
+    ) -> Result<&'a mut Qset<B>, A::Error> {
+        let mut qs = match data {
+            Some(qs) => qs,
+            None => {
+                let new = Self::new_qset(qset, alloc)?;
+                *qsets += 1;
+                data.insert(new)
+            }
+        };
+        for _ in 0..n {
+            qs = match qs.next {
+                Some(ref mut next) => next,
+                None => {
+                    let new = Self::new_qset(qset, alloc)?;
+                    *qsets += 1;
+                    qs.next.insert(new)
+                }
+            };
+        }
+        Ok(qs)
+    }
+
+    /// The block holding byte `pos`, allocated with `alloc` if missing, along
+    /// with the qsets before it. The size is left alone.
+    pub fn block_alloc<A: Alloc<B>>(&mut self, pos: usize, alloc: &mut A) -> Result<&mut B, A::Error> {
+        let locus = self.geometry.locate(pos);
+        let qs = Self::follow(&mut self.data, &mut self.qsets, self.geometry.qset, locus.item, alloc)?;
+        let slot = &mut qs.data[locus.slot];
+        if slot.is_none() {
+            *slot = Some(alloc.block()?);
+            self.blocks += 1;
+        }
+        Ok(slot.as_mut().unwrap())
+    }
+
+    /// Drop everything and switch to `geometry`. Returns the number of blocks freed.
+    pub fn reset(&mut self, geometry: Geometry) -> usize {
+        let freed = self.blocks;
+        unlink(self.data.take());
+        self.geometry = geometry;
+        self.size = 0;
+        self.blocks = 0;
+        self.qsets = 0;
+        freed
+    }
+
+    /// Drop everything, keeping the geometry. Returns the number of blocks freed.
+    pub fn trim(&mut self) -> usize {
+        self.reset(self.geometry)
+    }
+
+    /// Free up to `max` blocks, leaving holes that read back as zeros. The
+    /// size doesn't change. Returns the number of blocks freed.
+    pub fn reclaim(&mut self, max: usize) -> usize {
+        let mut freed = 0;
+        for (_, slot) in self.slots_mut() {
+            if freed >= max {
+                break;
+            }
+            if slot.take().is_some() {
+                freed += 1;
+            }
+        }
+        self.blocks -= freed;
+        freed
+    }
+
+    /// Read up to `count` bytes at `pos`, clamped to the size. `f` gets each
+    /// piece in turn, within a single block or hole, and returns how much of it
+    /// it took. A short piece ends the read. An error is only returned if
+    /// nothing was read, otherwise it is left for the next call.
+    pub fn read_with<E>(
+        &self,
+        pos: usize,
+        count: usize,
+        mut f: impl FnMut(Option<&B>, usize, usize) -> Result<usize, E>,
+    ) -> Result<usize, E> {
+        if pos >= self.size {
+            return Ok(0); // End of file
+        }
+        let count = count.min(self.size - pos);
+
+        let mut done = 0;
+        while done < count {
+            let at = pos + done;
+            let chunk = self.geometry.chunk(at, count - done);
+            match f(self.block_at(at), self.geometry.locate(at).offset, chunk) {
+                Ok(n) => {
+                    done += n;
+                    if n < chunk {
+                        break;
+                    }
+                }
+                Err(_) if done > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+
+    /// Allocate with `alloc` the blocks covering `count` bytes at `pos`, without
+    /// writing anything or changing the size. Returns how many bytes from `pos`
+    /// on are backed: a failed allocation stops there, and its error is only
+    /// returned if that's none of them.
+    pub fn reserve<A: Alloc<B>>(&mut self, pos: usize, count: usize, mut alloc: A) -> Result<usize, A::Error> {
+        let mut done = 0;
+        while done < count {
+            let at = pos + done;
+            match self.block_alloc(at, &mut alloc) {
+                Ok(_) => done += self.geometry.chunk(at, count - done),
+                Err(_) if done > 0 => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(done)
+    }
+
+    /// Store `count` bytes at `pos` in the blocks already there. `f` gets each
+    /// piece in turn, within a single block, and returns how much of it it
+    /// stored. A short piece or a hole ends the write. Nothing is allocated and
+    /// the size is left alone, see `reserve` and `grow`.
+    pub fn fill_with(&mut self, pos: usize, count: usize, mut f: impl FnMut(&mut B, usize, usize) -> usize) -> usize {
+        let geometry = self.geometry;
+        let mut done = 0;
+        while done < count {
+            let at = pos + done;
+            let chunk = geometry.chunk(at, count - done);
+            let block = match self.block_at_mut(at) {
+                Some(block) => block,
+                None => break,
+            };
+            let n = f(block, geometry.locate(at).offset, chunk);
+            done += n;
+            if n < chunk {
+                break;
+            }
+        }
+        done
+    }
+
+    /// Grow to `size`, leaving a sparse gap. A smaller size is ignored.
+    pub fn grow(&mut self, size: usize) {
+        self.size = self.size.max(size);
+    }
+
+    /// Write `count` bytes at `pos`, allocating blocks with `alloc`: `reserve`,
+    /// then `fill_with`, then the size grows to cover what was written. Blocks
+    /// reserved past a short piece stay allocated.
+    pub fn write_with<A: Alloc<B>>(
+        &mut self,
+        pos: usize,
+        count: usize,
+        alloc: A,
+        f: impl FnMut(&mut B, usize, usize) -> usize,
+    ) -> Result<usize, A::Error> {
+        let count = self.reserve(pos, count, alloc)?;
+        let done = self.fill_with(pos, count, f);
+        if done > 0 {
+            self.grow(pos + done);
+        }
+        Ok(done)
+    }
+}
+
+impl<B> Drop for Store<B> {
+    fn drop(&mut self) {
+        unlink(self.data.take());
+    }
+}
+
+impl<B: Block> Store<B> {
+    /// Shrink or grow to `new_size` without touching the data before it.
+    /// Growing leaves a sparse gap. Returns the number of blocks freed.
+    pub fn truncate(&mut self, new_size: usize) -> usize {
+        if new_size >= self.size {
+            self.size = new_size;
+            return 0;
+        }
+
+        // Free every block past the new end
+        let keep_blocks = self.geometry.blocks_for(new_size);
+        let keep_qsets = keep_blocks.div_ceil(self.geometry.qset);
+        let mut freed = 0;
+        for (index, slot) in self.slots_mut() {
+            if index >= keep_blocks && slot.take().is_some() {
+                freed += 1;
+            }
+        }
+
+        // And the qsets that are now empty
+        if keep_qsets == 0 {
+            unlink(self.data.take());
+        } else {
+            let mut last = self.data.as_deref_mut();
+            for _ in 1..keep_qsets {
+                last = last.and_then(|qs| qs.next.as_deref_mut());
+            }
+            if let Some(qs) = last {
+                unlink(qs.next.take());
+            }
+        }
+        self.qsets = self.qsets.min(keep_qsets);
+
+        // The tail of the last block must read back as zeros if the store grows again
+        let quantum = self.geometry.quantum;
+        if let Some(block) = self.block_at_mut(new_size) {
+            block.zero_from(new_size % quantum);
+        }
+
+        self.blocks -= freed;
+        self.size = new_size;
+        freed
+    }
+
+    /// Read into `buf` at `pos`, holes read as zeros. Returns the bytes read.
+    pub fn read(&self, pos: usize, buf: &mut [u8]) -> usize {
+        let mut done = 0;
+        let ret = self.read_with(pos, buf.len(), |block, offset, len| {
+            let dst = &mut buf[done..done + len];
+            match block {
+                Some(block) => block.read_at(offset, dst),
+                None => dst.fill(0),
+            }
+            done += len;
+            Ok::<_, Infallible>(len)
+        });
+        match ret {
+            Ok(n) => n,
+            Err(never) => match never {},
+        }
+    }
+
+    /// Write `buf` at `pos`, allocating blocks with `alloc`.
+    pub fn write<A: Alloc<B>>(&mut self, pos: usize, buf: &[u8], alloc: A) -> Result<usize, A::Error> {
+        let mut done = 0;
+        self.write_with(pos, buf.len(), alloc, |block, offset, len| {
+            block.write_at(offset, &buf[done..done + len]);
+            done += len;
+            len
+        })
+    }
+
+    /// Write `buf` at the current end.
+    pub fn append<A: Alloc<B>>(&mut self, buf: &[u8], alloc: A) -> Result<usize, A::Error> {
+        self.write(self.size, buf, alloc)
+    }
+}
@@ -0,0 +1,277 @@
+// Property tests of the store against a plain Vec<u8> holding the same bytes
+
+use proptest::prelude::*;
+use scull_core::{Alloc, AllocError as QsetError, Block, Geometry, Store};
+
+#[derive(Debug)]
+struct VecBlock(Vec<u8>);
+
+impl Block for VecBlock {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.0[offset..offset + buf.len()]);
+    }
+
+    fn write_at(&mut self, offset: usize, buf: &[u8]) {
+        self.0[offset..offset + buf.len()].copy_from_slice(buf);
+    }
+
+    fn zero_from(&mut self, offset: usize) {
+        self.0[offset..].fill(0);
+    }
+}
+
+#[derive(Debug)]
+enum AllocError {
+    Exhausted,
+    Reserve,
+}
+
+impl From<QsetError> for AllocError {
+    fn from(_: QsetError) -> Self {
+        AllocError::Reserve
+    }
+}
+
+// Hands out at most `budget` blocks
+fn allocator(quantum: usize, budget: &mut usize) -> impl FnMut() -> Result<VecBlock, AllocError> + '_ {
+    move || {
+        if *budget == 0 {
+            return Err(AllocError::Exhausted);
+        }
+        *budget -= 1;
+        Ok(VecBlock(vec![0; quantum]))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Op {
+    Write { pos: usize, data: Vec<u8>, budget: usize },
+    Append { data: Vec<u8> },
+    Truncate { size: usize },
+    Reclaim { max: usize },
+    Trim,
+    Read { pos: usize, len: usize },
+}
+
+const MAX_POS: usize = 300;
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        4 => (0..MAX_POS, prop::collection::vec(any::<u8>(), 0..64), 0..8usize)
+            .prop_map(|(pos, data, budget)| Op::Write { pos, data, budget }),
+        2 => prop::collection::vec(any::<u8>(), 0..64).prop_map(|data| Op::Append { data }),
+        2 => (0..MAX_POS).prop_map(|size| Op::Truncate { size }),
+        1 => (0..4usize).prop_map(|max| Op::Reclaim { max }),
+        1 => Just(Op::Trim),
+        3 => (0..MAX_POS + 32, 0..96usize).prop_map(|(pos, len)| Op::Read { pos, len }),
+    ]
+}
+
+fn check(store: &Store<VecBlock>, model: &[u8]) {
+    assert_eq!(store.size(), model.len());
+
+    let mut buf = vec![0xaa; model.len()];
+    assert_eq!(store.read(0, &mut buf), model.len());
+    assert_eq!(buf, model);
+
+    let geometry = store.geometry();
+    let blocks: Vec<usize> = store.blocks().map(|(index, _)| index).collect();
+    assert_eq!(blocks.len(), store.block_count());
+    assert!(blocks.windows(2).all(|w| w[0] < w[1]), "blocks out of order: {:?}", blocks);
+    assert!(blocks.iter().all(|&index| index < geometry.blocks_for(model.len())), "block past the end: {:?}", blocks);
+    let walked: Vec<usize> =
+        std::iter::successors(store.next_block(0), |&(index, _)| store.next_block(index + 1)).map(|(index, _)| index).collect();
+    assert_eq!(walked, blocks);
+    assert_eq!(store.mem_used(), store.block_count() * geometry.quantum());
+    assert_eq!(store.qset_count(), store.qsets().count());
+}
+
+proptest! {
+    #[test]
+    fn matches_vec_model(quantum in 1..16usize, qset in 1..5usize, ops in prop::collection::vec(op(), 1..40)) {
+        let geometry = Geometry::new(quantum, qset).unwrap();
+        let mut store = Store::new(geometry);
+        let mut model: Vec<u8> = Vec::new();
+
+        for op in ops {
+            match op {
+                Op::Write { pos, data, mut budget } => {
+                    let ret = store.write(pos, &data, allocator(quantum, &mut budget));
+                    let written = match ret {
+                        Ok(n) => n,
+                        Err(AllocError::Exhausted) => 0,
+                        Err(AllocError::Reserve) => panic!("qset allocation failed"),
+                    };
+                    assert!(written <= data.len());
+                    if written > 0 && written < data.len() {
+                        // Only a missing block stops a write half way, and always on a block boundary
+                        assert_eq!((pos + written) % quantum, 0);
+                    }
+                    if written > 0 {
+                        if model.len() < pos + written {
+                            model.resize(pos + written, 0);
+                        }
+                        model[pos..pos + written].copy_from_slice(&data[..written]);
+                    }
+                }
+                Op::Append { data } => {
+                    let mut budget = usize::MAX;
+                    let n = store.append(&data, allocator(quantum, &mut budget)).unwrap();
+                    assert_eq!(n, data.len());
+                    model.extend_from_slice(&data);
+                }
+                Op::Truncate { size } => {
+                    let before = store.block_count();
+                    let freed = store.truncate(size);
+                    assert_eq!(store.block_count(), before - freed);
+                    model.resize(size, 0);
+                }
+                Op::Reclaim { max } => {
+                    let before = store.block_count();
+                    let freed = store.reclaim(max);
+                    assert_eq!(freed, max.min(before));
+                    assert_eq!(store.block_count(), before - freed);
+                    // Dropped blocks read back as zeros
+                    for index in 0..geometry.blocks_for(model.len()) {
+                        if store.block_at(index * quantum).is_none() {
+                            let end = ((index + 1) * quantum).min(model.len());
+                            model[index * quantum..end].fill(0);
+                        }
+                    }
+                }
+                Op::Trim => {
+                    let before = store.block_count();
+                    assert_eq!(store.trim(), before);
+                    assert_eq!(store.qsets().count(), 0);
+                    model.clear();
+                }
+                Op::Read { pos, len } => {
+                    let mut buf = vec![0xaa; len];
+                    let n = store.read(pos, &mut buf);
+                    let expected = model.get(pos..).map_or(&[][..], |tail| &tail[..len.min(tail.len())]);
+                    assert_eq!(&buf[..n], expected);
+                }
+            }
+            check(&store, &model);
+        }
+    }
+
+    #[test]
+    fn locate_covers_every_byte_once(quantum in 1..64usize, qset in 1..8usize, pos in 0..10_000usize) {
+        let geometry = Geometry::new(quantum, qset).unwrap();
+        let locus = geometry.locate(pos);
+        prop_assert!(locus.slot < qset);
+        prop_assert!(locus.offset < quantum);
+        prop_assert_eq!(locus.item * geometry.itemsize() + locus.slot * quantum + locus.offset, pos);
+        prop_assert_eq!(geometry.block_index(pos), locus.item * qset + locus.slot);
+        prop_assert_eq!(geometry.chunk(pos, usize::MAX), quantum - locus.offset);
+    }
+}
+
+#[test]
+fn geometry_rejects_degenerate_values() {
+    assert!(Geometry::new(0, 1).is_none());
+    assert!(Geometry::new(1, 0).is_none());
+    assert!(Geometry::new(usize::MAX, 2).is_none());
+    assert!(Geometry::new(4096, 1000).is_some());
+}
+
+#[test]
+fn sparse_write_allocates_only_touched_blocks() {
+    let mut store = Store::new(Geometry::new(8, 2).unwrap());
+    let mut budget = usize::MAX;
+    assert_eq!(store.write(100, b"abc", allocator(8, &mut budget)).unwrap(), 3);
+    assert_eq!(store.size(), 103);
+    assert_eq!(store.block_count(), 1);
+    assert_eq!(store.blocks().map(|(index, _)| index).collect::<Vec<_>>(), [12]);
+
+    let mut buf = [0xaa; 103];
+    assert_eq!(store.read(0, &mut buf), 103);
+    assert!(buf[..100].iter().all(|&b| b == 0));
+    assert_eq!(&buf[100..], b"abc");
+}
+
+#[test]
+fn failed_allocation_returns_the_error_only_when_nothing_was_written() {
+    let mut store = Store::new(Geometry::new(4, 4).unwrap());
+    let mut budget = 0;
+    assert!(matches!(store.write(0, b"abcdef", allocator(4, &mut budget)), Err(AllocError::Exhausted)));
+    assert_eq!(store.size(), 0);
+
+    let mut budget = 1;
+    assert_eq!(store.write(2, b"abcdef", allocator(4, &mut budget)).unwrap(), 2);
+    assert_eq!(store.size(), 4);
+}
+
+// Lets blocks through but no more than `qsets` qsets
+struct QsetBudget {
+    qsets: usize,
+}
+
+impl Alloc<VecBlock> for QsetBudget {
+    type Error = AllocError;
+
+    fn block(&mut self) -> Result<VecBlock, AllocError> {
+        Ok(VecBlock(vec![0; 4]))
+    }
+
+    fn qset(&mut self, bytes: usize) -> Result<(), AllocError> {
+        assert!(bytes > 0);
+        if self.qsets == 0 {
+            return Err(AllocError::Exhausted);
+        }
+        self.qsets -= 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn qsets_on_the_way_to_a_sparse_write_go_through_the_allocator() {
+    let mut store = Store::new(Geometry::new(4, 2).unwrap());
+    // Offset 100 is in the 13th qset
+    assert!(matches!(store.write(100, b"abc", QsetBudget { qsets: 12 }), Err(AllocError::Exhausted)));
+    assert_eq!(store.size(), 0);
+    assert_eq!(store.block_count(), 0);
+    assert_eq!(store.qset_count(), 12);
+    let qset_mem = store.qset_mem();
+
+    // The ones already linked in stay, only the last is missing
+    assert_eq!(store.write(100, b"abc", QsetBudget { qsets: 1 }).unwrap(), 3);
+    assert_eq!(store.qset_count(), 13);
+    assert_eq!(store.qset_mem(), qset_mem / 12 * 13);
+}
+
+#[test]
+fn truncate_zeroes_the_tail_of_the_last_block() {
+    let mut store = Store::new(Geometry::new(8, 2).unwrap());
+    let mut budget = usize::MAX;
+    store.write(0, b"0123456789", allocator(8, &mut budget)).unwrap();
+    assert_eq!(store.truncate(3), 1);
+    assert_eq!(store.truncate(10), 0);
+
+    let mut buf = [0xaa; 10];
+    assert_eq!(store.read(0, &mut buf), 10);
+    assert_eq!(&buf, b"012\0\0\0\0\0\0\0");
+}
+
+#[test]
+fn truncate_unlinks_a_long_qset_chain_without_recursing() {
+    // One block per qset, so the chain is as long as the store
+    let mut store = Store::new(Geometry::new(1, 1).unwrap());
+    let mut budget = usize::MAX;
+    store.write(999_999, b"x", allocator(1, &mut budget)).unwrap();
+    assert_eq!(store.qsets().count(), 1_000_000);
+
+    assert_eq!(store.truncate(1), 1);
+    assert_eq!(store.qsets().count(), 1);
+    assert_eq!(store.truncate(0), 0);
+    assert_eq!(store.qsets().count(), 0);
+}
+
+#[test]
+fn dropping_a_long_qset_chain_does_not_recurse() {
+    let mut store = Store::new(Geometry::new(1, 1).unwrap());
+    let mut budget = usize::MAX;
+    store.write(999_999, b"x", allocator(1, &mut budget)).unwrap();
+    drop(store);
+}
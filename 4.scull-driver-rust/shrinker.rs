@@ -0,0 +1,85 @@
+use kernel::prelude::*;
+use kernel::pages::PAGE_SIZE;
+use kernel::shrinker::{ShrinkControl, Shrinker, ShrinkerRegistration, SHRINK_STOP};
+use kernel::sync::Arc;
+use core::sync::atomic::Ordering;
+
+use crate::{ScullDev, ScullRegistry};
+
+// Under memory pressure, devices marked "cache" through sysfs lose their
+// blocks. Objects are counted in pages. Reclaim can't sleep on a device, so
+// busy or mapped ones are just skipped until the next scan.
+#[derive(Debug)]
+pub(crate) struct ScullShrinker {
+    _reg: ShrinkerRegistration, // Unregistered on drop, before the registry can go
+    _registry: Arc<ScullRegistry>,
+}
+
+impl ScullShrinker {
+    pub(crate) fn register(registry: Arc<ScullRegistry>) -> Result<Self> {
+        Ok(ScullShrinker {
+            _reg: ShrinkerRegistration::register::<ScullShrinkerOps>("scull", &*registry)?,
+            _registry: registry,
+        })
+    }
+}
+
+// Run `f` on every device that can be marked cache, until it returns false.
+// Fails if a device list is busy.
+fn for_each_dev(registry: &ScullRegistry, mut f: impl FnMut(&mut ScullDev) -> bool) -> Result {
+    let mut devs = registry.devs.try_lock().ok_or(Error::EBUSY)?;
+    for slot in devs.iter_mut().flatten() {
+        if !f(&mut *slot.dev) {
+            return Ok(());
+        }
+    }
+    drop(devs);
+
+    let mut scullc_devs = registry.scullc_devs.try_lock().ok_or(Error::EBUSY)?;
+    for slot in scullc_devs.iter_mut().flatten() {
+        if !f(&mut *slot.dev) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) struct ScullShrinkerOps;
+
+impl Shrinker for ScullShrinkerOps {
+    type Data = ScullRegistry;
+
+    fn count_objects(registry: &ScullRegistry, _sc: &ShrinkControl) -> usize {
+        let mut pages = 0;
+        let _ = for_each_dev(registry, |dev| {
+            if dev.sem.down_read_trylock() {
+                if dev.cache && dev.vmas.load(Ordering::Relaxed) == 0 {
+                    pages += dev.store.mem_used() / PAGE_SIZE; // Blocks only, qsets stay
+                }
+                dev.sem.up_read();
+            }
+            true
+        });
+        pages
+    }
+
+    fn scan_objects(registry: &ScullRegistry, sc: &ShrinkControl) -> usize {
+        let wanted = sc.nr_to_scan() * PAGE_SIZE;
+        let mut freed = 0;
+        let ret = for_each_dev(registry, |dev| {
+            if dev.sem.down_write_trylock() {
+                if dev.cache {
+                    freed += dev.reclaim(wanted - freed);
+                }
+                dev.sem.up_write();
+            }
+            freed < wanted
+        });
+        if ret.is_err() {
+            return SHRINK_STOP;
+        }
+
+        pr_debug!("shrinker: dropped {} bytes\n", freed);
+        freed / PAGE_SIZE
+    }
+}
@@ -0,0 +1,190 @@
+use kernel::prelude::*;
+use kernel::user_ptr::{UserSlicePtr, UserSlicePtrReader, UserSlicePtrWriter};
+use core::sync::atomic::Ordering;
+
+use crate::scull_core::Block;
+use crate::ScullDev;
+
+// Snapshot blob layout, all fields little endian:
+//
+//   header:  magic "SCUL", version u32, quantum u64, qset u64, size u64, nblocks u64
+//   nblocks: index u64, fill u64, then `quantum` bytes of data
+//
+// Only allocated blocks are stored, so holes stay holes after a restore.
+const SNAPSHOT_MAGIC: [u8; 4] = *b"SCUL";
+const SNAPSHOT_VERSION: u32 = 1;
+const SNAPSHOT_HEADER_LEN: usize = 40;
+const SNAPSHOT_BLOCK_HEADER_LEN: usize = 16;
+
+// Argument of SCULL_IOCGSNAPSHOT and SCULL_IOCRESTORE
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct ScullSnapshotArg {
+    pub(crate) addr: u64, // User buffer holding the blob
+    pub(crate) len: u64,  // Its length, set to the needed length by SCULL_IOCGSNAPSHOT
+}
+
+fn read_arg(arg: usize) -> Result<ScullSnapshotArg> {
+    let mut raw = [0u8; 16];
+    UserSlicePtr::new(arg, raw.len()).reader().read_slice(&mut raw)?;
+    Ok(ScullSnapshotArg {
+        addr: u64::from_ne_bytes(raw[0..8].try_into().unwrap()),
+        len: u64::from_ne_bytes(raw[8..16].try_into().unwrap()),
+    })
+}
+
+fn write_len(arg: usize, len: usize) -> Result {
+    // Only `len` changes, it sits right after `addr`
+    UserSlicePtr::new(arg + 8, 8).writer().write_slice(&(len as u64).to_ne_bytes())
+}
+
+fn read_u64(reader: &mut UserSlicePtrReader) -> Result<u64> {
+    let mut raw = [0u8; 8];
+    reader.read_slice(&mut raw)?;
+    Ok(u64::from_le_bytes(raw))
+}
+
+fn write_u64(writer: &mut UserSlicePtrWriter, val: u64) -> Result {
+    writer.write_slice(&val.to_le_bytes())
+}
+
+fn block_buf(quantum: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::try_with_capacity(quantum)?;
+    buf.try_resize(quantum, 0u8)?;
+    Ok(buf)
+}
+
+// Copy the device into the user buffer. If it is too small nothing is copied,
+// `len` is set to the size needed and EOVERFLOW is returned.
+//
+// User memory may fault on a mapping of this device, or just be slow, so it is
+// never touched under the semaphore: each block is read into a kernel buffer
+// with the semaphore held, then copied out with it dropped. A device that
+// changes in between fails the snapshot with EAGAIN, the caller tries again.
+pub(crate) fn snapshot(dev: &ScullDev, arg: usize) -> Result<i32> {
+    let user = read_arg(arg)?;
+
+    dev.sem.down_read_interruptible()?;
+    let changes = dev.changes;
+    let quantum = dev.store.quantum();
+    let qset = dev.store.qset();
+    let size = dev.store.size();
+    let nblocks = dev.store.block_count();
+    dev.sem.up_read();
+
+    let needed = SNAPSHOT_HEADER_LEN + nblocks * (SNAPSHOT_BLOCK_HEADER_LEN + quantum);
+    if (user.len as usize) < needed {
+        write_len(arg, needed)?;
+        return Err(Error::EOVERFLOW);
+    }
+
+    let mut buf = block_buf(quantum)?;
+    let mut writer = UserSlicePtr::new(user.addr as usize, needed).writer();
+    writer.write_slice(&SNAPSHOT_MAGIC)?;
+    writer.write_slice(&SNAPSHOT_VERSION.to_le_bytes())?;
+    write_u64(&mut writer, quantum as u64)?;
+    write_u64(&mut writer, qset as u64)?;
+    write_u64(&mut writer, size as u64)?;
+    write_u64(&mut writer, nblocks as u64)?;
+
+    let mut next = 0;
+    for _ in 0..nblocks {
+        dev.sem.down_read_interruptible()?;
+        let ret = (|| -> Result<(usize, usize)> {
+            if dev.changes != changes {
+                return Err(Error::EAGAIN);
+            }
+            // Same block count as in the header, so there is one more
+            let (index, pblock) = dev.store.next_block(next).ok_or(Error::EAGAIN)?;
+            if dev.vmas.load(Ordering::Relaxed) == 0 {
+                pblock.verify()?; // Don't hand out a corrupt fixture
+            }
+            pblock.read_at(0, &mut buf);
+            Ok((index, pblock.offset))
+        })();
+        dev.sem.up_read();
+        let (index, fill) = ret?;
+
+        write_u64(&mut writer, index as u64)?;
+        write_u64(&mut writer, fill as u64)?;
+        writer.write_slice(&buf)?;
+        next = index + 1;
+    }
+
+    write_len(arg, needed)?;
+    pr_debug!("snapshot: {} blocks, {} bytes\n", nblocks, needed);
+    Ok(0)
+}
+
+// Replace the contents and geometry of the device with a blob from `snapshot`
+pub(crate) fn restore(dev: &mut ScullDev, arg: usize) -> Result<i32> {
+    let user = read_arg(arg)?;
+    let mut reader = UserSlicePtr::new(user.addr as usize, user.len as usize).reader();
+
+    let mut magic = [0u8; 4];
+    reader.read_slice(&mut magic)?;
+    let mut version = [0u8; 4];
+    reader.read_slice(&mut version)?;
+    if magic != SNAPSHOT_MAGIC || u32::from_le_bytes(version) != SNAPSHOT_VERSION {
+        return Err(Error::EINVAL);
+    }
+
+    let quantum = read_u64(&mut reader)? as usize;
+    let qset = read_u64(&mut reader)? as usize;
+    let size = read_u64(&mut reader)? as usize;
+    let nblocks = read_u64(&mut reader)? as usize;
+    let geometry = ScullDev::geometry(quantum, qset)?;
+    // `nblocks` comes from the user, don't let it overflow
+    let expected = nblocks
+        .checked_mul(SNAPSHOT_BLOCK_HEADER_LEN + quantum)
+        .and_then(|len| len.checked_add(SNAPSHOT_HEADER_LEN))
+        .ok_or(Error::EINVAL)?;
+    if user.len as usize != expected {
+        return Err(Error::EINVAL);
+    }
+    dev.check_quantum(quantum)?;
+
+    dev.sem.down_write_interruptible()?;
+    let mut buf = match block_buf(quantum).and_then(|buf| dev.reset(geometry).map(|()| buf)) {
+        Ok(buf) => buf,
+        Err(e) => {
+            dev.sem.up_write();
+            return Err(e);
+        }
+    };
+
+    let mut alloc = dev.allocator();
+    let ret = (|| -> Result {
+        for _ in 0..nblocks {
+            let index = read_u64(&mut reader)? as usize;
+            let fill = read_u64(&mut reader)? as usize;
+            reader.read_slice(&mut buf)?;
+            let pos = index.checked_mul(quantum).ok_or(Error::EINVAL)?;
+            if fill > quantum || pos >= size {
+                return Err(Error::EINVAL);
+            }
+            let _index = dev.index.lock();
+            let pblock = dev.store.block_alloc(pos, &mut alloc)?;
+            pblock.write_at(0, &buf);
+            pblock.offset = fill;
+        }
+        Ok(())
+    })();
+
+    match ret {
+        Ok(()) => {
+            let _index = dev.index.lock();
+            dev.store.grow(size); // Past the last block
+        }
+        // Don't leave half a fixture behind
+        Err(_) => {
+            let _ = dev.trim();
+        }
+    }
+    dev.sem.up_write();
+    ret?;
+
+    dev.waitq.wake_up_interruptible();
+    pr_debug!("restore: {} blocks, size {}\n", nblocks, size);
+    Ok(0)
+}
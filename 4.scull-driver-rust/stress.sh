@@ -0,0 +1,32 @@
+#! /bin/sh
+# Fill a scull device and read it back from many readers at once.
+#
+# Readers only take the device semaphore shared, so the aggregate throughput
+# with N readers should scale well past that of a single one.
+device=${1:-/dev/scull0}
+readers=${2:-16}
+size_mb=100
+bs=4096
+count=$((size_mb * 1024 * 1024 / bs))
+
+now_ns() {
+    date +%s%N
+}
+
+read_all() {
+    start=$(now_ns)
+    for i in $(seq 1 $1); do
+        dd if=$device of=/dev/null bs=$bs count=$count 2>/dev/null &
+    done
+    wait
+    end=$(now_ns)
+    elapsed_ms=$(((end - start) / 1000000))
+    echo "$1 reader(s): $(($1 * size_mb)) MB in ${elapsed_ms} ms, $(($1 * size_mb * 1000 / (elapsed_ms + 1))) MB/s"
+}
+
+dd if=/dev/zero of=$device bs=$bs count=$count 2>/dev/null || exit 1
+
+read_all 1
+read_all $readers
+
+: > $device
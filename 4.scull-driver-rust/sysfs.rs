@@ -0,0 +1,163 @@
+use kernel::prelude::*;
+use kernel::sysfs::{Attribute, AttributeGroup, ClassAttribute, ClassAttributeGroup, SysfsBuf};
+use kernel::sysfs_emit;
+use core::sync::atomic::Ordering;
+
+use crate::{ScullDev, SCULL_MEM_LIMIT, SCULL_MEM_USED, SCULL_QSET_CUR, SCULL_RECLAIMED};
+
+// The class creates /sys/class/scull/<node> for every device node, and
+// udev/devtmpfs creates the matching /dev entries from it
+pub(crate) const SCULL_CLASS_NAME: &str = "scull";
+
+fn parse_usize(buf: &[u8]) -> Result<usize> {
+    core::str::from_utf8(buf)
+        .map_err(|_| Error::EINVAL)?
+        .trim()
+        .parse()
+        .map_err(|_| Error::EINVAL)
+}
+
+fn size_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    dev.sem.down_read_interruptible()?;
+    let len = sysfs_emit!(buf, "{}\n", dev.store.size());
+    dev.sem.up_read();
+    Ok(len)
+}
+
+fn blocks_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    dev.sem.down_read_interruptible()?;
+    let len = sysfs_emit!(buf, "{}\n", dev.store.block_count());
+    dev.sem.up_read();
+    Ok(len)
+}
+
+fn open_count_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    Ok(sysfs_emit!(buf, "{}\n", dev.open_count.load(Ordering::Relaxed)))
+}
+
+fn block_size_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    dev.sem.down_read_interruptible()?;
+    let len = sysfs_emit!(buf, "{}\n", dev.store.quantum());
+    dev.sem.up_read();
+    Ok(len)
+}
+
+// The layout depends on the block size, so changing it drops the contents
+fn block_size_store(dev: &mut ScullDev, buf: &[u8]) -> Result<usize> {
+    let quantum = parse_usize(buf)?;
+    let geometry = ScullDev::geometry(quantum, SCULL_QSET_CUR.load(Ordering::Relaxed))?;
+    dev.check_quantum(quantum)?;
+
+    dev.sem.down_write_interruptible()?;
+    let ret = dev.reset(geometry);
+    if ret.is_ok() {
+        dev.quantum_pinned = true; // Keep it across later trims
+    }
+    dev.sem.up_write();
+    ret?;
+
+    Ok(buf.len())
+}
+
+fn mem_used_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    dev.sem.down_read_interruptible()?;
+    let len = sysfs_emit!(buf, "{}\n", dev.mem_used());
+    dev.sem.up_read();
+    Ok(len)
+}
+
+fn mem_limit_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    Ok(sysfs_emit!(buf, "{}\n", dev.mem_limit))
+}
+
+// Lowering the limit below the current usage only stops further growth
+fn mem_limit_store(dev: &mut ScullDev, buf: &[u8]) -> Result<usize> {
+    let limit = parse_usize(buf)?;
+    dev.sem.down_write_interruptible()?;
+    dev.mem_limit = limit;
+    dev.sem.up_write();
+    Ok(buf.len())
+}
+
+fn trim_store(dev: &mut ScullDev, buf: &[u8]) -> Result<usize> {
+    if parse_usize(buf)? != 1 {
+        return Err(Error::EINVAL);
+    }
+
+    dev.sem.down_write_interruptible()?;
+    let ret = dev.trim();
+    dev.sem.up_write();
+    ret?;
+
+    Ok(buf.len())
+}
+
+fn cache_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    Ok(sysfs_emit!(buf, "{}\n", dev.cache as usize))
+}
+
+// 1 lets the shrinker drop the contents under memory pressure
+fn cache_store(dev: &mut ScullDev, buf: &[u8]) -> Result<usize> {
+    let cache = match parse_usize(buf)? {
+        0 => false,
+        1 => true,
+        _ => return Err(Error::EINVAL),
+    };
+    dev.sem.down_write_interruptible()?;
+    dev.cache = cache;
+    dev.sem.up_write();
+    Ok(buf.len())
+}
+
+fn reclaimed_show(dev: &ScullDev, buf: &mut SysfsBuf) -> Result<usize> {
+    dev.sem.down_read_interruptible()?;
+    let len = sysfs_emit!(buf, "{}\n", dev.reclaimed);
+    dev.sem.up_read();
+    Ok(len)
+}
+
+pub(crate) struct ScullAttrs;
+
+impl AttributeGroup for ScullAttrs {
+    type Data = ScullDev;
+
+    const ATTRS: &'static [Attribute<ScullDev>] = &[
+        Attribute::ro("size", size_show),
+        Attribute::ro("blocks", blocks_show),
+        Attribute::ro("open_count", open_count_show),
+        Attribute::rw("block_size", block_size_show, block_size_store),
+        Attribute::wo("trim", trim_store),
+        Attribute::ro("mem_used", mem_used_show),
+        Attribute::rw("mem_limit", mem_limit_show, mem_limit_store),
+        Attribute::rw("cache", cache_show, cache_store),
+        Attribute::ro("reclaimed", reclaimed_show),
+    ];
+}
+
+// Module-wide usage and cap, directly under /sys/class/scull
+fn class_mem_used_show(buf: &mut SysfsBuf) -> Result<usize> {
+    Ok(sysfs_emit!(buf, "{}\n", SCULL_MEM_USED.load(Ordering::Relaxed)))
+}
+
+fn class_mem_limit_show(buf: &mut SysfsBuf) -> Result<usize> {
+    Ok(sysfs_emit!(buf, "{}\n", SCULL_MEM_LIMIT.load(Ordering::Relaxed)))
+}
+
+fn class_mem_limit_store(buf: &[u8]) -> Result<usize> {
+    SCULL_MEM_LIMIT.store(parse_usize(buf)?, Ordering::Relaxed);
+    Ok(buf.len())
+}
+
+fn class_reclaimed_show(buf: &mut SysfsBuf) -> Result<usize> {
+    Ok(sysfs_emit!(buf, "{}\n", SCULL_RECLAIMED.load(Ordering::Relaxed)))
+}
+
+pub(crate) struct ScullClassAttrs;
+
+impl ClassAttributeGroup for ScullClassAttrs {
+    const ATTRS: &'static [ClassAttribute] = &[
+        ClassAttribute::ro("mem_used", class_mem_used_show),
+        ClassAttribute::rw("mem_limit", class_mem_limit_show, class_mem_limit_store),
+        ClassAttribute::ro("reclaimed", class_reclaimed_show),
+    ];
+}